@@ -1,40 +1,508 @@
+#[cfg(feature = "clipboard")]
+use arboard;
+use evalexpr;
 use failure;
+use parser;
+use red;
 use regex::Regex;
+use serde_json;
+use serde_yaml;
+use similar::{capture_diff_slices, Algorithm, DiffOp, TextDiff};
 use std::cmp;
-use std::fs::{self, File};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::thread;
+use term_size;
+use tokenizer;
+use toml;
 use Red;
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Address {
     CurrentLine,
     LastLine,
     Numbered(usize),
     Offset(isize),
+    /// The line of a mark, moved by an offset
+    Mark(char, isize),
+    /// An address moved by an offset, as in `$-2`
+    Expr(Box<Address>, isize),
+    /// The last line of the block indented below the current line
+    BlockEnd,
+    /// The second address, resolved with the first one as the current line,
+    /// as the end of the range `1;+2`
+    Relative(Box<Address>, Box<Address>),
+    /// The next line matching a pattern, wrapping around to the start
+    ForwardSearch(String),
+    /// The previous line matching a pattern, wrapping around to the end
+    BackwardSearch(String),
+}
+
+/// A column within a line, counting from 1.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Column {
+    /// The column as displayed, with tabs expanded to the next tab stop.
+    Visual(usize),
+    /// The byte offset into the line.
+    Byte(usize),
+}
+
+impl Column {
+    /// Get the byte index into `line` this column refers to.
+    ///
+    /// Columns past the end of the line refer to its end.
+    /// A visual column inside a tab refers to the tab.
+    fn byte_index(self, line: &str, tab_width: usize) -> Result<usize, failure::Error> {
+        match self {
+            Column::Byte(n) => {
+                let idx = cmp::min(n - 1, line.len());
+                if !line.is_char_boundary(idx) {
+                    return Err(format_err!("Invalid column"));
+                }
+                Ok(idx)
+            }
+            Column::Visual(n) => {
+                let mut visual = 1;
+                for (idx, c) in line.char_indices() {
+                    let next = if c == '\t' && tab_width > 0 {
+                        visual + tab_width - (visual - 1) % tab_width
+                    } else {
+                        visual + 1
+                    };
+                    if n < next {
+                        return Ok(idx);
+                    }
+                    visual = next;
+                }
+                Ok(line.len())
+            }
+        }
+    }
+}
+
+/// A line and a column within it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Position {
+    pub line: Address,
+    pub column: Column,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Mode {
     Command,
     Input,
+    /// Reading the command to run on a line of an interactive global command
+    Global,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Action {
     Quit,
     Continue,
-    Unknown,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Error of a substitution that didn't match any line in its range.
+#[derive(Debug)]
+pub struct NoMatch;
+
+impl fmt::Display for NoMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No match")
+    }
+}
+
+impl failure::Fail for NoMatch {}
+
+/// How lines are formatted when written out.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum LineFormat {
+    /// The line as is.
+    Plain,
+    /// The line number, a tab and the line.
+    Numbered,
+    /// The right-aligned line number, a ` | ` separator and the line.
+    Snippet,
+    /// A marker whether the line is unchanged (` `), added (`+`) or modified (`~`)
+    /// since the file was loaded or saved, and the line.
+    Changes,
+    /// The line with non-printing characters escaped and a `$` at its end.
+    List,
+}
+
+/// How lines are formatted when copied to the clipboard.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ClipFormat {
+    /// The lines as is.
+    Raw,
+    /// Each line preceded by its line number and a tab.
+    Numbered,
+    /// The lines wrapped in a Markdown code fence.
+    Markdown,
+}
+
+impl FromStr for ClipFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<ClipFormat, failure::Error> {
+        match s {
+            "raw" => Ok(ClipFormat::Raw),
+            "numbered" => Ok(ClipFormat::Numbered),
+            "markdown" => Ok(ClipFormat::Markdown),
+            _ => Err(format_err!("Unknown clipboard format: {}", s)),
+        }
+    }
+}
+
+/// Data formats the buffer can be checked against.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum CheckFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl CheckFormat {
+    /// Guess the format from a file extension.
+    fn from_path(path: &str) -> Option<CheckFormat> {
+        let ext = Path::new(path).extension()?.to_str()?;
+        ext.to_lowercase().parse().ok()
+    }
+
+    /// Parse `text`, returning the parser's error message on failure.
+    fn check(self, text: &str) -> Result<(), String> {
+        match self {
+            CheckFormat::Json => serde_json::from_str::<serde_json::Value>(text)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            CheckFormat::Toml => toml::from_str::<toml::Value>(text)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            CheckFormat::Yaml => serde_yaml::from_str::<serde_yaml::Value>(text)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl FromStr for CheckFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<CheckFormat, failure::Error> {
+        match s {
+            "json" => Ok(CheckFormat::Json),
+            "toml" => Ok(CheckFormat::Toml),
+            "yaml" | "yml" => Ok(CheckFormat::Yaml),
+            _ => Err(format_err!("Unknown format: {}", s)),
+        }
+    }
+}
+
+/// How lines end in the file the buffer is written to.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum LineEnding {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+/// Delimiters used to wrap lines in a block.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BlockStyle {
+    /// `{` and `}`
+    Braces,
+    /// `do` and `end`
+    DoEnd,
+}
+
+impl BlockStyle {
+    fn delimiters(self) -> (&'static str, &'static str) {
+        match self {
+            BlockStyle::Braces => ("{", "}"),
+            BlockStyle::DoEnd => ("do", "end"),
+        }
+    }
+}
+
+impl FromStr for BlockStyle {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<BlockStyle, failure::Error> {
+        match s {
+            "braces" => Ok(BlockStyle::Braces),
+            "do-end" => Ok(BlockStyle::DoEnd),
+            _ => Err(format_err!("Unknown block style: {}", s)),
+        }
+    }
+}
+
+/// Case conversions applied by `~`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Case {
+    Upper,
+    Lower,
+    /// The first letter of each word in uppercase, the rest in lowercase
+    Title,
+}
+
+impl Case {
+    fn apply(self, text: &str) -> String {
+        match self {
+            Case::Upper => text.to_uppercase(),
+            Case::Lower => text.to_lowercase(),
+            Case::Title => {
+                let mut result = String::with_capacity(text.len());
+                let mut word_start = true;
+                for c in text.chars() {
+                    if word_start {
+                        result.extend(c.to_uppercase());
+                    } else {
+                        result.extend(c.to_lowercase());
+                    }
+                    // Don't start a new word after an apostrophe, as in "don't"
+                    word_start = !c.is_alphanumeric() && c != '\'';
+                }
+                result
+            }
+        }
+    }
+}
+
+/// String literal syntaxes `E` escapes lines for.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Escape {
+    /// The contents of a JSON string
+    Json,
+    /// A single-quoted POSIX shell word
+    Shell,
+    /// The contents of a C string literal
+    C,
+}
+
+impl Escape {
+    fn escape(self, text: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        match self {
+            Escape::Json => {
+                for c in text.chars() {
+                    match c {
+                        '"' => result.push_str("\\\""),
+                        '\\' => result.push_str("\\\\"),
+                        '\n' => result.push_str("\\n"),
+                        '\r' => result.push_str("\\r"),
+                        '\t' => result.push_str("\\t"),
+                        '\u{8}' => result.push_str("\\b"),
+                        '\u{c}' => result.push_str("\\f"),
+                        c if c.is_control() && (c as u32) < 0x10000 => {
+                            result.push_str(&format!("\\u{:04x}", c as u32))
+                        }
+                        c => result.push(c),
+                    }
+                }
+            }
+            Escape::Shell => {
+                result.push('\'');
+                result.push_str(&text.replace('\'', "'\\''"));
+                result.push('\'');
+            }
+            Escape::C => {
+                for c in text.chars() {
+                    match c {
+                        '"' => result.push_str("\\\""),
+                        '\\' => result.push_str("\\\\"),
+                        '\n' => result.push_str("\\n"),
+                        '\r' => result.push_str("\\r"),
+                        '\t' => result.push_str("\\t"),
+                        '\u{7}' => result.push_str("\\a"),
+                        '\u{8}' => result.push_str("\\b"),
+                        '\u{b}' => result.push_str("\\v"),
+                        '\u{c}' => result.push_str("\\f"),
+                        // Octal escapes end after three digits, unlike hex escapes
+                        c if c.is_ascii_control() => {
+                            result.push_str(&format!("\\{:03o}", c as u32))
+                        }
+                        c => result.push(c),
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn unescape(self, text: &str) -> Result<String, failure::Error> {
+        match self {
+            Escape::Json => serde_json::from_str::<String>(&format!("\"{}\"", text))
+                .map_err(|_| format_err!("Invalid escape")),
+            Escape::Shell => Self::unescape_shell(text),
+            Escape::C => Self::unescape_c(text),
+        }
+    }
+
+    /// Remove single quotes, double quotes and backslashes like the shell does for a word.
+    fn unescape_shell(text: &str) -> Result<String, failure::Error> {
+        let mut result = String::with_capacity(text.len());
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => loop {
+                    match chars.next() {
+                        None => return Err(format_err!("Invalid escape")),
+                        Some('\'') => break,
+                        Some(c) => result.push(c),
+                    }
+                },
+                '"' => loop {
+                    match chars.next() {
+                        None => return Err(format_err!("Invalid escape")),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            None => return Err(format_err!("Invalid escape")),
+                            Some(c) if "$`\"\\".contains(c) => result.push(c),
+                            Some(c) => {
+                                result.push('\\');
+                                result.push(c);
+                            }
+                        },
+                        Some(c) => result.push(c),
+                    }
+                },
+                '\\' => match chars.next() {
+                    None => return Err(format_err!("Invalid escape")),
+                    Some(c) => result.push(c),
+                },
+                c => result.push(c),
+            }
+        }
+        Ok(result)
+    }
+
+    fn unescape_c(text: &str) -> Result<String, failure::Error> {
+        let mut result = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+
+            let c = match chars.next() {
+                None => return Err(format_err!("Invalid escape")),
+                Some(c) => c,
+            };
+            let unescaped = match c {
+                'a' => '\u{7}',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'v' => '\u{b}',
+                '\\' | '\'' | '"' | '?' => c,
+                '0'..='7' => {
+                    let mut n = c.to_digit(8).unwrap();
+                    for _ in 0..2 {
+                        match chars.peek().and_then(|c| c.to_digit(8)) {
+                            None => break,
+                            Some(d) => n = n * 8 + d,
+                        }
+                        chars.next();
+                    }
+                    ::std::char::from_u32(n).ok_or_else(|| format_err!("Invalid escape"))?
+                }
+                'x' => {
+                    let mut n: u32 = 0;
+                    let mut digits = 0;
+                    while let Some(d) = chars.peek().and_then(|c| c.to_digit(16)) {
+                        n = n
+                            .checked_mul(16)
+                            .ok_or_else(|| format_err!("Invalid escape"))?
+                            + d;
+                        digits += 1;
+                        chars.next();
+                    }
+                    if digits == 0 {
+                        return Err(format_err!("Invalid escape"));
+                    }
+                    ::std::char::from_u32(n).ok_or_else(|| format_err!("Invalid escape"))?
+                }
+                _ => return Err(format_err!("Invalid escape")),
+            };
+            result.push(unescaped);
+        }
+        Ok(result)
+    }
+}
+
+impl FromStr for Escape {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Escape, failure::Error> {
+        match s {
+            "json" => Ok(Escape::Json),
+            "shell" | "sh" => Ok(Escape::Shell),
+            "c" => Ok(Escape::C),
+            _ => Err(format_err!("Unknown escape scheme: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Command {
     Noop,
+    Comment {
+        address: Option<Address>,
+    },
     Quit {
         force: bool,
     },
+    WriteQuit {
+        start: Option<Address>,
+        end: Option<Address>,
+        file: Option<String>,
+    },
     Help,
+    ToggleHelp,
+    Undo,
+    Unsaved,
+    Status,
+    SoftWrap,
+    TogglePrompt,
+    Filename {
+        name: Option<String>,
+    },
+    Global {
+        start: Option<Address>,
+        end: Option<Address>,
+        regex: String,
+        invert: bool,
+        command: String,
+    },
+    InteractiveGlobal {
+        start: Option<Address>,
+        end: Option<Address>,
+        regex: String,
+        invert: bool,
+    },
     Jump {
         address: Address,
+        silent: bool,
     },
     Print {
         start: Option<Address>,
@@ -44,27 +512,50 @@ pub enum Command {
         start: Option<Address>,
         end: Option<Address>,
     },
+    Snippet {
+        start: Option<Address>,
+        end: Option<Address>,
+    },
+    List {
+        start: Option<Address>,
+        end: Option<Address>,
+    },
+    Scroll {
+        start: Option<Address>,
+        count: Option<usize>,
+    },
+    Changes {
+        start: Option<Address>,
+        end: Option<Address>,
+    },
     Delete {
         start: Option<Address>,
         end: Option<Address>,
+        print: Option<LineFormat>,
     },
     Write {
         start: Option<Address>,
         end: Option<Address>,
         file: Option<String>,
+        force: bool,
+        append: bool,
     },
     Insert {
         before: Option<Address>,
+        print: Option<LineFormat>,
     },
     Append {
         after: Option<Address>,
+        print: Option<LineFormat>,
     },
     Edit {
         file: Option<String>,
+        force: bool,
     },
     Change {
         start: Option<Address>,
         end: Option<Address>,
+        print: Option<LineFormat>,
     },
     Read {
         after: Option<Address>,
@@ -74,35 +565,402 @@ pub enum Command {
         start: Option<Address>,
         end: Option<Address>,
         dest: Address,
+        print: Option<LineFormat>,
+    },
+    Join {
+        start: Option<Address>,
+        end: Option<Address>,
+        print: Option<LineFormat>,
+    },
+    Reflow {
+        start: Option<Address>,
+        end: Option<Address>,
+    },
+    Transfer {
+        start: Option<Address>,
+        end: Option<Address>,
+        dest: Address,
+        print: Option<LineFormat>,
     },
     Substitute {
         start: Option<Address>,
         end: Option<Address>,
         arg: Option<String>,
     },
+    Extract {
+        start: Option<Address>,
+        end: Option<Address>,
+        arg: Option<String>,
+    },
+    Rotate {
+        start: Option<Address>,
+        end: Option<Address>,
+        count: isize,
+    },
+    NumberLines {
+        start: Option<Address>,
+        end: Option<Address>,
+        width: Option<usize>,
+        separator: String,
+    },
+    LongLines {
+        start: Option<Address>,
+        end: Option<Address>,
+        width: Option<usize>,
+    },
+    Diff {
+        file: Option<String>,
+    },
+    Patch {
+        file: Option<String>,
+    },
+    Outline {
+        start: Option<Address>,
+        end: Option<Address>,
+        pattern: Option<String>,
+    },
+    Translate {
+        start: Option<Address>,
+        end: Option<Address>,
+        arg: Option<String>,
+    },
+    Escape {
+        start: Option<Address>,
+        end: Option<Address>,
+        scheme: Escape,
+        reverse: bool,
+    },
+    Align {
+        start: Option<Address>,
+        end: Option<Address>,
+        delimiter: Option<String>,
+    },
+    Cut {
+        start: Option<Address>,
+        end: Option<Address>,
+        from: Column,
+        to: Column,
+    },
+    ChangeCase {
+        start: Option<Address>,
+        end: Option<Address>,
+        case: Case,
+        pattern: Option<String>,
+    },
+    Clip {
+        start: Option<Address>,
+        end: Option<Address>,
+    },
+    Check,
+    Shell {
+        command: String,
+    },
+    Filter {
+        start: Option<Address>,
+        end: Option<Address>,
+        command: String,
+    },
+    Yank {
+        start: Option<Address>,
+        end: Option<Address>,
+        register: char,
+    },
+    Put {
+        after: Option<Address>,
+        register: char,
+        pattern: Option<String>,
+    },
+    Split {
+        position: Position,
+    },
+    InsertAt {
+        position: Position,
+        text: String,
+    },
+    Compress {
+        start: Option<Address>,
+        end: Option<Address>,
+        squeeze: bool,
+    },
+    LineNumber {
+        address: Option<Address>,
+    },
+    Evaluate {
+        address: Option<Address>,
+    },
+    RepeatInput {
+        after: Option<Address>,
+    },
+    Ruler {
+        after: Option<Address>,
+        fill: char,
+        width: Option<usize>,
+    },
+    Mark {
+        address: Option<Address>,
+        label: char,
+    },
+    Record {
+        file: Option<String>,
+    },
+    InsertEvery {
+        start: Option<Address>,
+        end: Option<Address>,
+        interval: usize,
+        text: String,
+    },
+    Block {
+        start: Option<Address>,
+        end: Option<Address>,
+    },
+    Interleave {
+        start: Option<Address>,
+        end: Option<Address>,
+        other_start: Address,
+        other_end: Address,
+    },
+    Transpose {
+        start: Option<Address>,
+        end: Option<Address>,
+        other_start: Address,
+        other_end: Address,
+    },
+    FormatJson {
+        start: Option<Address>,
+        end: Option<Address>,
+    },
 }
 
 impl Command {
+    /// Whether the command changes the buffer.
+    pub fn is_mutating(&self) -> bool {
+        use Command::*;
+
+        match *self {
+            Delete { .. }
+            | Insert { .. }
+            | Append { .. }
+            | Edit { .. }
+            | Change { .. }
+            | Read { .. }
+            | Move { .. }
+            | Join { .. }
+            | Reflow { .. }
+            | Transfer { .. }
+            | Substitute { .. }
+            | Rotate { .. }
+            | NumberLines { .. }
+            | Translate { .. }
+            | ChangeCase { .. }
+            | Cut { .. }
+            | Escape { .. }
+            | Align { .. }
+            | Put { .. }
+            | Split { .. }
+            | InsertAt { .. }
+            | Compress { .. }
+            | Block { .. }
+            | InsertEvery { .. }
+            | Interleave { .. }
+            | Evaluate { .. }
+            | Filter { .. }
+            | Ruler { .. }
+            | RepeatInput { .. }
+            | Undo
+            | Transpose { .. }
+            | FormatJson { .. } => true,
+            // A global command changes the buffer if one of the commands it runs does
+            Global { ref command, .. } => Self::command_list(command)
+                .map(|list| list.iter().any(|(command, _)| command.is_mutating()))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
     pub fn execute(self, ed: &mut Red) -> Result<Action, failure::Error> {
         debug!("Command::execute: {:?}", self);
         use Command::*;
 
         match self {
             Noop => Self::noop(ed),
+            Comment { address } => Self::comment(ed, address),
             Help => Self::help(ed),
+            ToggleHelp => {
+                ed.help_verbose = !ed.help_verbose;
+                if ed.help_verbose {
+                    Self::help(ed)
+                } else {
+                    Ok(Action::Continue)
+                }
+            }
+            Undo => Self::undo(ed),
+            Unsaved => Self::unsaved(ed),
+            Status => Self::status(ed),
+            SoftWrap => Self::toggle_soft_wrap(ed),
+            Filename { name } => Self::filename(ed, name),
+            TogglePrompt => {
+                ed.prompt_enabled = !ed.prompt_enabled;
+                Ok(Action::Continue)
+            }
+            Global {
+                start,
+                end,
+                regex,
+                invert,
+                command,
+            } => Self::global(ed, start, end, regex, invert, command),
+            InteractiveGlobal {
+                start,
+                end,
+                regex,
+                invert,
+            } => Self::interactive_global(ed, start, end, regex, invert),
             Quit { force } => Self::quit(ed, force),
-            Jump { address } => Self::jump(ed, address),
+            WriteQuit { start, end, file } => {
+                // Like `w` followed by `q`, which warns if lines weren't written
+                match Self::write(ed, start, end, file, false, false)? {
+                    Action::Continue => Self::quit(ed, false),
+                    action => Ok(action),
+                }
+            }
+            Jump { address, silent } => Self::jump(ed, address, silent),
             Print { start, end } => Self::print(ed, start, end),
             Numbered { start, end } => Self::numbered(ed, start, end),
-            Delete { start, end } => Self::delete(ed, start, end),
-            Write { start, end, file } => Self::write(ed, start, end, file),
-            Insert { before } => Self::insert(ed, before),
-            Append { after } => Self::append(ed, after),
-            Edit { file } => Self::edit(ed, file),
-            Change { start, end } => Self::change(ed, start, end),
+            Snippet { start, end } => Self::snippet(ed, start, end),
+            List { start, end } => Self::list(ed, start, end),
+            Scroll { start, count } => Self::scroll(ed, start, count),
+            Changes { start, end } => Self::changes(ed, start, end),
+            Delete { start, end, print } => {
+                Self::delete(ed, start, end)?;
+                Self::print_current(ed, print)
+            }
+            Write {
+                start,
+                end,
+                file,
+                force,
+                append,
+            } => Self::write(ed, start, end, file, force, append),
+            Insert { before, print } => Self::insert(ed, before, print),
+            Append { after, print } => Self::append(ed, after, print),
+            Edit { file, force } => Self::edit(ed, file, force),
+            Change { start, end, print } => Self::change(ed, start, end, print),
             Read { after, file } => Self::read(ed, after, file),
-            Move { start, end, dest } => Self::move_lines(ed, start, end, dest),
+            Move {
+                start,
+                end,
+                dest,
+                print,
+            } => {
+                Self::move_lines(ed, start, end, dest)?;
+                Self::print_current(ed, print)
+            }
+            Join { start, end, print } => {
+                Self::join(ed, start, end)?;
+                Self::print_current(ed, print)
+            }
+            Reflow { start, end } => Self::reflow(ed, start, end),
+            Transfer {
+                start,
+                end,
+                dest,
+                print,
+            } => {
+                Self::transfer(ed, start, end, dest)?;
+                Self::print_current(ed, print)
+            }
             Substitute { start, end, arg } => Self::substitute(ed, start, end, arg),
+            Extract { start, end, arg } => Self::extract(ed, start, end, arg),
+            Rotate { start, end, count } => Self::rotate(ed, start, end, count),
+            NumberLines {
+                start,
+                end,
+                width,
+                separator,
+            } => Self::number_lines(ed, start, end, width, separator),
+            LongLines { start, end, width } => Self::long_lines(ed, start, end, width),
+            Diff { file } => Self::diff(ed, file),
+            Patch { file } => Self::patch(ed, file),
+            Outline {
+                start,
+                end,
+                pattern,
+            } => Self::outline(ed, start, end, pattern),
+            Translate { start, end, arg } => Self::translate(ed, start, end, arg),
+            ChangeCase {
+                start,
+                end,
+                case,
+                pattern,
+            } => Self::change_case(ed, start, end, case, pattern),
+            Cut {
+                start,
+                end,
+                from,
+                to,
+            } => Self::cut(ed, start, end, from, to),
+            Align {
+                start,
+                end,
+                delimiter,
+            } => Self::align(ed, start, end, delimiter),
+            Escape {
+                start,
+                end,
+                scheme,
+                reverse,
+            } => Self::escape(ed, start, end, scheme, reverse),
+            Clip { start, end } => Self::clip(ed, start, end),
+            Check => Self::check(ed),
+            Shell { command } => Self::shell(ed, &command),
+            Filter {
+                start,
+                end,
+                command,
+            } => Self::filter(ed, start, end, &command),
+            Yank {
+                start,
+                end,
+                register,
+            } => Self::yank(ed, start, end, register),
+            Put {
+                after,
+                register,
+                pattern,
+            } => Self::put(ed, after, register, pattern),
+            Split { position } => Self::split(ed, position),
+            InsertAt { position, text } => Self::insert_at(ed, position, text),
+            Compress {
+                start,
+                end,
+                squeeze,
+            } => Self::compress(ed, start, end, squeeze),
+            LineNumber { address } => Self::line_number(ed, address),
+            FormatJson { start, end } => Self::format_json(ed, start, end),
+            Evaluate { address } => Self::evaluate(ed, address),
+            Ruler { after, fill, width } => Self::ruler(ed, after, fill, width),
+            RepeatInput { after } => Self::repeat_input(ed, after),
+            Mark { address, label } => Self::mark(ed, address, label),
+            Record { file } => Self::record(ed, file),
+            Block { start, end } => Self::block(ed, start, end),
+            InsertEvery {
+                start,
+                end,
+                interval,
+                text,
+            } => Self::insert_every(ed, start, end, interval, text),
+            Interleave {
+                start,
+                end,
+                other_start,
+                other_end,
+            } => Self::interleave(ed, start, end, other_start, other_end),
+            Transpose {
+                start,
+                end,
+                other_start,
+                other_end,
+            } => Self::transpose(ed, start, end, other_start, other_end),
         }
     }
 
@@ -111,8 +969,17 @@ impl Command {
             ed.current_line += 1;
             Self::print(ed, None, None)
         } else {
-            Ok(Action::Unknown)
+            Err(format_err!("Invalid address"))
+        }
+    }
+
+    fn comment(ed: &mut Red, address: Option<Address>) -> Result<Action, failure::Error> {
+        // A comment does nothing, but an address still moves the current line
+        if let Some(addr) = address {
+            let line = Self::get_actual_line(ed, addr)?;
+            ed.set_line(line)?;
         }
+        Ok(Action::Continue)
     }
 
     fn help(ed: &mut Red) -> Result<Action, failure::Error> {
@@ -122,16 +989,267 @@ impl Command {
         Ok(Action::Continue)
     }
 
-    fn quit(ed: &mut Red, force: bool) -> Result<Action, failure::Error> {
-        if !force && ed.dirty {
-            ed.dirty = false;
-            Err(format_err!("Warning: buffer modified"))
+    fn undo(ed: &mut Red) -> Result<Action, failure::Error> {
+        let (data, line, dirty) = match ed.undo_buffer.take() {
+            None => return Err(format_err!("Nothing to undo")),
+            Some(state) => state,
+        };
+
+        // Keep the current state around, so undoing again redoes the change
+        let data = mem::replace(&mut ed.data, data);
+        let line = mem::replace(&mut ed.current_line, line);
+        let dirty = mem::replace(&mut ed.dirty, dirty);
+        ed.data_changed(&data);
+        ed.undo_buffer = Some((data, line, dirty));
+
+        Ok(Action::Continue)
+    }
+
+    fn global(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        regex: String,
+        invert: bool,
+        command: String,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let re = Self::remember_pattern(ed, &regex)?;
+        let list = Self::command_list(&command)?;
+
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::Numbered(1)), Some(Address::LastLine)),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+
+        // Find all lines first, the command might change the lines below
+        let mut lines: Vec<usize> = (start..=end)
+            .filter(|&line| re.is_match(&ed.data[line - 1]) != invert)
+            .collect();
+        lines.reverse();
+        ed.global_lines = lines;
+
+        let result = Self::run_global(ed, &list);
+        ed.global_lines.clear();
+        result
+    }
+
+    /// Run the command list of a global command on each line left to visit.
+    ///
+    /// Like in ed, a substitution that doesn't match a line goes on with the next one,
+    /// and only fails if it matched none of them. Any other error, or an interrupt,
+    /// leaves the buffer as it was before.
+    fn run_global(ed: &mut Red, list: &[(Command, Vec<String>)]) -> Result<Action, failure::Error> {
+        let before = (ed.data.clone(), ed.current_line, ed.dirty, ed.marks.clone());
+        let mut matched = false;
+        let mut unmatched = false;
+        while let Some(line) = ed.global_lines.pop() {
+            ed.current_line = line;
+            let result = if ed.interrupted.load(Ordering::SeqCst) {
+                Err(format_err!("Interrupted"))
+            } else {
+                Self::run_command_list(ed, list)
+            };
+            match result {
+                Ok(()) => matched = true,
+                Err(ref e) if e.downcast_ref::<NoMatch>().is_some() => unmatched = true,
+                Err(e) => {
+                    let (data, line, dirty, marks) = before;
+                    ed.data = data;
+                    ed.current_line = line;
+                    ed.dirty = dirty;
+                    ed.marks = marks;
+                    ed.mode = Mode::Command;
+                    return Err(e);
+                }
+            }
+        }
+
+        if unmatched && !matched {
+            return Err(NoMatch.into());
+        }
+        Ok(Action::Continue)
+    }
+
+    /// Run the command list of a global command on the current line.
+    fn run_command_list(
+        ed: &mut Red,
+        list: &[(Command, Vec<String>)],
+    ) -> Result<(), failure::Error> {
+        for (command, text) in list {
+            command.clone().execute(ed)?;
+            // `a`, `i` and `c` take their text from the command list
+            if ed.mode == Mode::Input {
+                for line in text {
+                    ed.insert_line(line);
+                }
+                ed.mode = Mode::Command;
+                let print = ed.input_print.take();
+                Self::print_current(ed, print)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the command list of a global command into its commands, each with the
+    /// lines of text it takes if it is `a`, `i` or `c`.
+    ///
+    /// Every line of the list but the last ends with a backslash. The `.` ending
+    /// the text of the last command can be left out. An empty list prints the lines.
+    fn command_list(command: &str) -> Result<Vec<(Command, Vec<String>)>, failure::Error> {
+        let mut lines = command
+            .split('\n')
+            .map(|line| line.strip_suffix('\\').unwrap_or(line));
+
+        let mut list = vec![];
+        while let Some(command) = lines.next() {
+            let command = command.trim();
+            if command.is_empty() {
+                continue;
+            }
+            let mut text = vec![];
+            let command = Self::parse_listed(command)?;
+            match command {
+                Command::Global { .. } | Command::InteractiveGlobal { .. } => {
+                    return Err(format_err!("Invalid command"))
+                }
+                Command::Insert { .. } | Command::Append { .. } | Command::Change { .. } => {
+                    for line in &mut lines {
+                        if line == "." {
+                            break;
+                        }
+                        text.push(line.to_string());
+                    }
+                }
+                _ => {}
+            }
+            list.push((command, text));
+        }
+        if list.is_empty() {
+            let print = Command::Print {
+                start: None,
+                end: None,
+            };
+            list.push((print, vec![]));
+        }
+        Ok(list)
+    }
+
+    fn parse_listed(command: &str) -> Result<Command, failure::Error> {
+        parser::parse(&tokenizer::tokenize(command)?)
+    }
+
+    /// Visit the addressed lines matching `regex` (or not matching it, if `invert` is set)
+    /// one after the other, reading a command to run on each of them.
+    fn interactive_global(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        regex: String,
+        invert: bool,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let re = Self::remember_pattern(ed, &regex)?;
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::Numbered(1)), Some(Address::LastLine)),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+
+        let mut lines: Vec<usize> = (start..=end)
+            .filter(|&line| re.is_match(&ed.data[line - 1]) != invert)
+            .collect();
+        lines.reverse();
+        ed.global_lines = lines;
+        ed.global_snapshot = Some((ed.data.clone(), ed.current_line, ed.dirty));
+        ed.next_global_line()
+    }
+
+    fn status(ed: &mut Red) -> Result<Action, failure::Error> {
+        println!("{}", Self::status_line(ed));
+        Ok(Action::Continue)
+    }
+
+    /// Summarize the editing state in one line: the file, the current line and how
+    /// far it is through the buffer, whether there are unsaved changes and the line ending.
+    fn status_line(ed: &Red) -> String {
+        let path = ed.path.as_ref().map(|p| &p[..]).unwrap_or("[no file]");
+        let percent = match ed.lines() {
+            0 => 0,
+            lines => ed.current_line * 100 / lines,
+        };
+        let state = if ed.dirty { "modified" } else { "saved" };
+        let ending = match ed.line_ending {
+            LineEnding::Lf => "lf",
+            LineEnding::CrLf => "crlf",
+        };
+
+        format!(
+            "{}: line {} of {} ({}%), {}, {}",
+            path,
+            ed.current_line,
+            ed.lines(),
+            percent,
+            state,
+            ending
+        )
+    }
+
+    fn unsaved(ed: &mut Red) -> Result<Action, failure::Error> {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        Self::write_unsaved(handle, &ed.original, &ed.data)?;
+        Ok(Action::Continue)
+    }
+
+    /// Write how many lines were added, removed or modified from `old` to `new`
+    /// and how the size in bytes changed.
+    fn write_unsaved<W: Write>(
+        mut output: W,
+        old: &[String],
+        new: &[String],
+    ) -> Result<(), failure::Error> {
+        let mut changed = 0;
+        for op in capture_diff_slices(Algorithm::Myers, old, new) {
+            changed += match op {
+                DiffOp::Equal { .. } => 0,
+                DiffOp::Insert { new_len, .. } => new_len,
+                DiffOp::Delete { old_len, .. } => old_len,
+                DiffOp::Replace {
+                    old_len, new_len, ..
+                } => cmp::max(old_len, new_len),
+            };
+        }
+
+        let size = |lines: &[String]| lines.iter().map(|l| l.len() as isize + 1).sum::<isize>();
+        writeln!(
+            output,
+            "{} line{} changed, {:+} bytes",
+            changed,
+            if changed == 1 { "" } else { "s" },
+            size(new) - size(old)
+        )?;
+        Ok(())
+    }
+
+    fn quit(ed: &mut Red, force: bool) -> Result<Action, failure::Error> {
+        // Quitting again right after the warning discards the changes
+        if !force && ed.dirty && !ed.quit_warned {
+            ed.quit_warned = true;
+            Err(format_err!("Warning: buffer modified"))
         } else {
             Ok(Action::Quit)
         }
     }
 
-    fn jump(ed: &mut Red, addr: Address) -> Result<Action, failure::Error> {
+    fn jump(ed: &mut Red, addr: Address, silent: bool) -> Result<Action, failure::Error> {
         use self::Address::*;
         match addr {
             CurrentLine => { /* Don't jump at all */ }
@@ -147,6 +1265,15 @@ impl Command {
                 }
                 ed.set_line(new_line as usize)?;
             }
+            Mark(..) | Expr(..) | Relative(..) | BlockEnd | ForwardSearch(_)
+            | BackwardSearch(_) => {
+                let new_line = Self::get_actual_line(ed, addr)?;
+                ed.set_line(new_line)?
+            }
+        }
+
+        if silent {
+            return Ok(Action::Continue);
         }
 
         // After a jump, print the current line
@@ -158,9 +1285,24 @@ impl Command {
         start: Option<Address>,
         end: Option<Address>,
     ) -> Result<Action, failure::Error> {
-        let stdout = io::stdout();
-        let handle = stdout.lock();
-        Self::write_range(handle, ed, start, end, false)
+        Self::print_range(ed, start, end, LineFormat::Plain)
+    }
+
+    /// Print the current line in `format` after a command with a print suffix, as in `2dp`.
+    ///
+    /// Nothing is printed if there is no current line left, or if `--auto-print`
+    /// already prints it as is.
+    pub fn print_current(
+        ed: &mut Red,
+        format: Option<LineFormat>,
+    ) -> Result<Action, failure::Error> {
+        match format {
+            Some(LineFormat::Plain) if ed.auto_print => Ok(Action::Continue),
+            Some(format) if ed.get_line(ed.current_line).is_some() => {
+                Self::print_range(ed, None, None, format)
+            }
+            _ => Ok(Action::Continue),
+        }
     }
 
     fn numbered(
@@ -168,95 +1310,274 @@ impl Command {
         start: Option<Address>,
         end: Option<Address>,
     ) -> Result<Action, failure::Error> {
-        let stdout = io::stdout();
-        let handle = stdout.lock();
-        Self::write_range(handle, ed, start, end, true)
+        Self::print_range(ed, start, end, LineFormat::Numbered)
     }
 
-    fn delete(
+    fn snippet(
         ed: &mut Red,
         start: Option<Address>,
         end: Option<Address>,
     ) -> Result<Action, failure::Error> {
-        if ed.data.is_empty() {
+        Self::print_range(ed, start, end, LineFormat::Snippet)
+    }
+
+    fn list(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<Action, failure::Error> {
+        Self::print_range(ed, start, end, LineFormat::List)
+    }
+
+    /// Print a window of lines starting at `start`, by default the line after the current one.
+    ///
+    /// A `count` sets the window size for this and following scrolls,
+    /// before that it's the height of the terminal.
+    fn scroll(
+        ed: &mut Red,
+        start: Option<Address>,
+        count: Option<usize>,
+    ) -> Result<Action, failure::Error> {
+        if count.is_some() {
+            ed.window = count;
+        }
+        // Leave a line of the terminal for the prompt
+        let window = ed.window.unwrap_or_else(|| {
+            term_size::dimensions()
+                .map(|(_, h)| cmp::max(h, 2) - 1)
+                .unwrap_or(22)
+        });
+        let start = match start {
+            None => ed.current_line + 1,
+            Some(addr) => Self::get_actual_line(ed, addr)?,
+        };
+        if start == 0 || start > ed.lines() {
             return Err(format_err!("Invalid address"));
         }
+        let end = cmp::min(start + window - 1, ed.lines());
 
-        match (start, end) {
-            (None, None) => {
-                let line = ed.current_line;
-                ed.data.remove(line - 1);
-                ed.dirty = true;
-                ed.current_line = cmp::min(line, ed.data.len());
-            }
+        let (start, end) = (Address::Numbered(start), Address::Numbered(end));
+        Self::print_range(ed, Some(start), Some(end), LineFormat::Plain)
+    }
 
-            (Some(start), None) => {
-                let line = Self::get_actual_line(&ed, start)?;
-                ed.data.remove(line - 1);
-                ed.dirty = true;
-                ed.current_line = cmp::min(line, ed.data.len());
-            }
+    fn line_number(ed: &mut Red, address: Option<Address>) -> Result<Action, failure::Error> {
+        let line = Self::get_actual_line(ed, address.unwrap_or(Address::LastLine))?;
+        if line == 0 {
+            println!("0");
+        } else {
+            println!("{}", ed.display_line_number(line));
+        }
+        Ok(Action::Continue)
+    }
 
-            (None, Some(end)) => {
-                let end = Self::get_actual_line(&ed, end)?;
+    fn evaluate(ed: &mut Red, address: Option<Address>) -> Result<Action, failure::Error> {
+        let (line, _) = Self::get_actual_range(ed, address, None)?;
+        let result = match evalexpr::eval(&ed.data[line - 1]) {
+            Ok(evalexpr::Value::Empty) | Err(_) => return Err(format_err!("Invalid expression")),
+            Ok(value) => value,
+        };
 
-                for _ in 1..=end {
-                    ed.data.remove(0);
-                }
+        ed.data.insert(line, format!("= {}", result));
+        ed.lines_replaced(line, 0, 1);
+        ed.current_line = line + 1;
+        ed.dirty = true;
 
-                ed.dirty = true;
-                ed.current_line = cmp::min(end, ed.data.len());
-            }
+        Ok(Action::Continue)
+    }
 
-            (Some(start), Some(end)) => {
-                let start = Self::get_actual_line(&ed, start)?;
-                let end = Self::get_actual_line(&ed, end)?;
+    fn repeat_input(ed: &mut Red, after: Option<Address>) -> Result<Action, failure::Error> {
+        let line = Self::get_actual_line(ed, after.unwrap_or(Address::CurrentLine))?;
+        if ed.last_input.is_empty() {
+            return Err(format_err!("No previous input"));
+        }
 
-                for _ in start..=end {
-                    ed.data.remove(start - 1);
-                }
+        let lines = ed.last_input.clone();
+        ed.current_line = line + lines.len();
+        ed.lines_replaced(line, 0, lines.len());
+        ed.data.splice(line..line, lines);
+        ed.dirty = true;
 
-                ed.dirty = true;
-                ed.current_line = cmp::min(start, ed.data.len());
+        Ok(Action::Continue)
+    }
+
+    fn ruler(
+        ed: &mut Red,
+        after: Option<Address>,
+        fill: char,
+        width: Option<usize>,
+    ) -> Result<Action, failure::Error> {
+        let line = Self::get_actual_line(ed, after.unwrap_or(Address::CurrentLine))?;
+        let width = width.unwrap_or_else(|| term_size::dimensions().map(|(w, _)| w).unwrap_or(80));
+
+        ed.data.insert(line, fill.to_string().repeat(width));
+        ed.lines_replaced(line, 0, 1);
+        ed.current_line = line + 1;
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    fn filename(ed: &mut Red, name: Option<String>) -> Result<Action, failure::Error> {
+        if let Some(name) = name {
+            // The new file has nothing to do with the one loaded
+            ed.disk_stat = None;
+            ed.path = Some(name);
+        }
+        match ed.path {
+            None => Err(format_err!("No current filename")),
+            Some(ref path) => {
+                println!("{}", path);
+                Ok(Action::Continue)
             }
         }
+    }
+
+    fn toggle_soft_wrap(ed: &mut Red) -> Result<Action, failure::Error> {
+        ed.soft_wrap = match ed.soft_wrap {
+            Some(_) => None,
+            None => Some(term_size::dimensions().map(|(w, _)| w).unwrap_or(80)),
+        };
+        Ok(Action::Continue)
+    }
+
+    fn mark(ed: &mut Red, address: Option<Address>, label: char) -> Result<Action, failure::Error> {
+        let (line, _) = Self::get_actual_range(ed, address, None)?;
+        ed.marks.insert(label, line);
+        Ok(Action::Continue)
+    }
+
+    fn record(ed: &mut Red, file: Option<String>) -> Result<Action, failure::Error> {
+        let file = match file {
+            None => return Err(format_err!("No current filename")),
+            Some(file) => file,
+        };
+
+        let mut output = File::create(&file)?;
+        for line in &ed.history {
+            writeln!(output, "{}", line)?;
+        }
+        let size = fs::metadata(&file)?.len();
+        if !ed.silent {
+            println!("{}", size);
+        }
+
+        Ok(Action::Continue)
+    }
+
+    fn changes(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<Action, failure::Error> {
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        Self::write_range(handle, ed, start, end, LineFormat::Changes)
+    }
+
+    fn delete(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        // The range is checked first, so nothing is removed from a reversed range like `5,2`
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        // The lines are kept in the unnamed register, `x` puts them back
+        let lines = ed.data.drain(start - 1..end).collect();
+        ed.lines_replaced(start - 1, end - start + 1, 0);
+        ed.registers.insert(parser::DEFAULT_REGISTER, lines);
+        ed.dirty = true;
+        ed.current_line = cmp::min(start, ed.data.len());
         Ok(Action::Continue)
     }
 
     fn write(
         ed: &mut Red,
-        mut start: Option<Address>,
-        mut end: Option<Address>,
+        start: Option<Address>,
+        end: Option<Address>,
         file: Option<String>,
+        force: bool,
+        append: bool,
     ) -> Result<Action, failure::Error> {
         let file = file.or_else(|| ed.path.clone());
-        match file {
-            None => Ok(Action::Unknown),
-            Some(path) => {
-                // By default, write the whole buffer
-                if start.is_none() && end.is_none() {
-                    start = Some(Address::Numbered(1));
-                    end = Some(Address::LastLine);
+        let file = match file {
+            None => return Err(format_err!("No current filename")),
+            Some(file) => file,
+        };
+        // `w !command` pipes the lines to a command, the buffer isn't saved by that
+        let path = match file.strip_prefix('!') {
+            Some(command) => return Self::write_command(ed, start, end, command),
+            None => file,
+        };
+
+        // Don't clobber changes made on disk since the file was loaded.
+        // The check is only done once, so writing again overrides it.
+        if ed.safe_write && !force && ed.path.as_ref() == Some(&path) {
+            if let Some(stat) = ed.disk_stat.take() {
+                if red::file_stat(&path) != Some(stat) {
+                    return Err(format_err!("Warning: file modified on disk"));
                 }
+            }
+        }
 
-                debug!("Writing to file {:?} ({:?}..{:?})", path, start, end);
+        // The range is checked before the file is touched
+        let whole = start.is_none() && end.is_none();
+        let (first, last) = Self::write_bounds(ed, start, end)?;
 
-                let file = File::create(&path)?;
-                Self::write_range(file, ed, start, end, false)?;
-                let size = fs::metadata(&path)?.len();
-                println!("{}", size);
+        debug!("Writing to file {:?} ({:?}..{:?})", path, first, last);
 
-                ed.path = Some(path);
-                ed.dirty = false;
+        // Only the bytes written now are reported when appending
+        let (file, before) = if append {
+            let before = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            let file = OpenOptions::new().append(true).create(true).open(&path)?;
+            (file, before)
+        } else {
+            (File::create(&path)?, 0)
+        };
+        Self::write_lines(file, ed, first, last)?;
+        let size = fs::metadata(&path)?.len() - before;
+        if ed.verbose && !ed.silent {
+            let lines = last + 1 - first;
+            println!(
+                "wrote {} line{} ({} bytes) to {}",
+                lines,
+                if lines == 1 { "" } else { "s" },
+                size,
+                path
+            );
+        } else if !ed.silent {
+            println!("{}", size);
+        }
 
-                Ok(Action::Continue)
-            }
+        // Like in ed, the file becomes the current filename only if there is none.
+        // Appending to a file, like a log, never makes it the current one.
+        if ed.path.is_none() && !append {
+            ed.path = Some(path.clone());
+        }
+        if ed.path.as_ref() == Some(&path) {
+            ed.disk_stat = red::file_stat(&path);
         }
+        // Changes are only saved if all of the buffer was written
+        if first == 1 && last == ed.lines() {
+            ed.dirty = false;
+        }
+        if whole && !append {
+            ed.original = ed.data.clone();
+        }
+
+        Ok(Action::Continue)
     }
 
-    fn insert(ed: &mut Red, before: Option<Address>) -> Result<Action, failure::Error> {
+    fn insert(
+        ed: &mut Red,
+        before: Option<Address>,
+        print: Option<LineFormat>,
+    ) -> Result<Action, failure::Error> {
         let mut addr = before
-            .map(|addr| Self::get_actual_line(&ed, addr))
+            .map(|addr| Self::get_actual_line(ed, addr))
             .unwrap_or_else(|| Ok(ed.current_line))?;
         // Insert after the previous line
         if addr > 0 {
@@ -264,26 +1585,47 @@ impl Command {
         }
         ed.current_line = addr;
         ed.mode = Mode::Input;
+        ed.input_print = print;
         Ok(Action::Continue)
     }
 
-    fn append(ed: &mut Red, after: Option<Address>) -> Result<Action, failure::Error> {
+    fn append(
+        ed: &mut Red,
+        after: Option<Address>,
+        print: Option<LineFormat>,
+    ) -> Result<Action, failure::Error> {
         let addr = after
-            .map(|addr| Self::get_actual_line(&ed, addr))
+            .map(|addr| Self::get_actual_line(ed, addr))
             .unwrap_or_else(|| Ok(ed.current_line))?;
         ed.current_line = addr;
         ed.mode = Mode::Input;
+        ed.input_print = print;
         Ok(Action::Continue)
     }
 
-    fn edit(ed: &mut Red, file: Option<String>) -> Result<Action, failure::Error> {
+    fn edit(ed: &mut Red, file: Option<String>, force: bool) -> Result<Action, failure::Error> {
+        // Unsaved changes are only thrown away with `e!`
+        if ed.dirty && !force {
+            return Err(format_err!("Warning: buffer modified"));
+        }
         let file = file.or_else(|| ed.path.clone());
 
         let file = match file {
             None => return Err(format_err!("No current filename")),
             Some(file) => file,
         };
-        ed.load_file(file)?;
+        // `e !command` edits the output of a command, the filename stays the same
+        match file.strip_prefix('!') {
+            Some(command) => {
+                let data = Self::command_output(ed, command)?;
+                ed.replace_data(data);
+            }
+            None => ed.load_file(file)?,
+        }
+        ed.dirty = false;
+        if !ed.silent {
+            println!("{}", ed.data_size());
+        }
 
         Ok(Action::Continue)
     }
@@ -292,6 +1634,7 @@ impl Command {
         ed: &mut Red,
         start: Option<Address>,
         end: Option<Address>,
+        print: Option<LineFormat>,
     ) -> Result<Action, failure::Error> {
         Self::delete(ed, start, end)?;
         let mut addr = ed.current_line;
@@ -300,6 +1643,7 @@ impl Command {
         }
         ed.current_line = addr;
         ed.mode = Mode::Input;
+        ed.input_print = print;
         ed.dirty = true;
         Ok(Action::Continue)
     }
@@ -315,13 +1659,18 @@ impl Command {
             None => return Err(format_err!("No current filename")),
             Some(file) => file,
         };
-        let data = ed.load_data(&file)?;
+        // `r !command` reads the output of a command
+        let data = match file.strip_prefix('!') {
+            Some(command) => Self::command_output(ed, command)?,
+            None => ed.load_data(&file)?,
+        };
 
         let mut addr = after
-            .map(|addr| Self::get_actual_line(&ed, addr))
+            .map(|addr| Self::get_actual_line(ed, addr))
             .unwrap_or_else(|| Ok(ed.current_line))?;
 
         let mut written = 0;
+        ed.lines_replaced(addr, 0, data.len());
         for line in data {
             written += line.len() + 1;
             if ed.data.is_empty() {
@@ -332,45 +1681,154 @@ impl Command {
             addr += 1;
         }
 
-        ed.dirty = true;
-        ed.current_line = addr;
-        println!("{}", written);
+        if written > 0 {
+            ed.dirty = true;
+            ed.current_line = addr;
+        }
+        if !ed.silent {
+            println!("{}", written);
+        }
 
         Ok(Action::Continue)
     }
 
-    fn move_lines(
+    fn join(
         ed: &mut Red,
         start: Option<Address>,
         end: Option<Address>,
-        dest: Address,
     ) -> Result<Action, failure::Error> {
-        if ed.data.is_empty() {
+        // By default, join the current line with the next one
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::CurrentLine), Some(Address::Offset(1))),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        if start == end {
             return Ok(Action::Continue);
         }
 
-        let mut dest = Self::get_actual_line(&ed, dest)?;
-        debug!("Moving after line {}", dest);
+        let joined: String = ed.data.drain(start..end).collect();
+        ed.lines_replaced(start, end - start, 0);
+        ed.data[start - 1].push_str(&joined);
+        ed.current_line = start;
+        ed.dirty = true;
 
-        match (start, end) {
-            (None, None) => {
-                let line_no = ed.current_line;
-                debug!("Moving line {} to {}", line_no, dest);
-                if line_no == dest {
-                    return Err(format_err!("Invalid destination"));
-                }
-                let line = ed.data.remove(line_no - 1);
-                if dest > line_no {
-                    dest -= 1;
-                }
+        Ok(Action::Continue)
+    }
 
-                debug!("After adjustment: Moving line {} to {}", line_no, dest);
-                ed.data.insert(dest, line);
-                ed.set_line(dest)?;
-            }
+    fn reflow(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<Action, failure::Error> {
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+
+        let lines = ed.data.splice(start - 1..end, vec![]).collect::<Vec<_>>();
+        let wrapped = Self::wrap_paragraph(&lines, ed.wrap_width);
+        ed.current_line = start + wrapped.len() - 1;
+        ed.lines_replaced(start - 1, lines.len(), wrapped.len());
+        ed.data.splice(start - 1..start - 1, wrapped);
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    /// Join `lines` into a single paragraph and break it at word boundaries so that
+    /// no line is longer than `width` characters, unless a single word is.
+    ///
+    /// The leading whitespace common to all non-blank lines is kept on every line.
+    fn wrap_paragraph(lines: &[String], width: usize) -> Vec<String> {
+        let indent = lines
+            .iter()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| &l[..l.len() - l.trim_start().len()])
+            .fold(None, |common: Option<&str>, indent| match common {
+                None => Some(indent),
+                Some(common) => {
+                    let len = common
+                        .char_indices()
+                        .zip(indent.chars())
+                        .take_while(|((_, a), b)| a == b)
+                        .last()
+                        .map(|((i, c), _)| i + c.len_utf8())
+                        .unwrap_or(0);
+                    Some(&common[..len])
+                }
+            })
+            .unwrap_or("");
+
+        let mut wrapped = vec![];
+        let mut line = String::new();
+        for word in lines.iter().flat_map(|l| l.split_whitespace()) {
+            if line.is_empty() {
+                line.push_str(indent);
+            } else if line.chars().count() + 1 + word.chars().count() > width {
+                wrapped.push(line);
+                line = indent.to_string();
+            } else {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        if !line.is_empty() || wrapped.is_empty() {
+            wrapped.push(line);
+        }
+        wrapped
+    }
+
+    fn transfer(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        dest: Address,
+    ) -> Result<Action, failure::Error> {
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let dest = Self::get_actual_line(ed, dest)?;
+        debug!("Copying lines {}..{} after line {}", start, end, dest);
+
+        // The source stays, so only the lines after the destination shift
+        let lines = ed.data[start - 1..end].to_vec();
+        ed.current_line = dest + lines.len();
+        ed.lines_replaced(dest, 0, lines.len());
+        ed.data.splice(dest..dest, lines);
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    fn move_lines(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        dest: Address,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Ok(Action::Continue);
+        }
+
+        let mut dest = Self::get_actual_line(ed, dest)?;
+        debug!("Moving after line {}", dest);
+
+        match (start, end) {
+            (None, None) => {
+                let line_no = ed.current_line;
+                debug!("Moving line {} to {}", line_no, dest);
+                if line_no == dest {
+                    return Err(format_err!("Invalid destination"));
+                }
+                let line = ed.data.remove(line_no - 1);
+                if dest > line_no {
+                    dest -= 1;
+                }
+
+                debug!("After adjustment: Moving line {} to {}", line_no, dest);
+                ed.data.insert(dest, line);
+                ed.lines_moved(line_no - 1, 1, dest);
+                ed.set_line(dest)?;
+            }
 
             (Some(start), None) => {
-                let line_no = Self::get_actual_line(&ed, start)?;
+                let line_no = Self::get_actual_line(ed, start)?;
                 debug!("Moving line {} to {}", line_no, dest);
                 if line_no == dest {
                     return Err(format_err!("Invalid destination"));
@@ -381,13 +1839,14 @@ impl Command {
                 }
                 debug!("After adjustment: Moving line {} to {}", line_no, dest);
                 ed.data.insert(dest, line);
+                ed.lines_moved(line_no - 1, 1, dest);
                 let dest = cmp::max(dest, 1);
                 ed.set_line(dest)?;
             }
 
             (None, Some(end)) => {
                 let mut lines = vec![];
-                let end = Self::get_actual_line(&ed, end)?;
+                let end = Self::get_actual_line(ed, end)?;
                 debug!("Moving lines 1..{} to {}", end, dest);
 
                 if dest <= end {
@@ -400,6 +1859,7 @@ impl Command {
 
                 dest -= lines.len();
                 debug!("New destination after adjustment: {}", dest);
+                ed.lines_moved(0, lines.len(), dest);
                 for line in lines {
                     ed.data.insert(dest, line);
                     dest += 1;
@@ -410,8 +1870,8 @@ impl Command {
 
             (Some(start), Some(end)) => {
                 let mut lines = vec![];
-                let start = Self::get_actual_line(&ed, start)?;
-                let end = Self::get_actual_line(&ed, end)?;
+                let start = Self::get_actual_line(ed, start)?;
+                let end = Self::get_actual_line(ed, end)?;
                 debug!("Moving lines {}..{} to {}", start, end, dest);
 
                 if dest >= start && dest <= end {
@@ -426,6 +1886,7 @@ impl Command {
                     dest -= lines.len();
                 }
                 debug!("New destination after adjustment: {}", dest);
+                ed.lines_moved(start - 1, lines.len(), dest);
                 for line in lines {
                     ed.data.insert(dest, line);
                     dest += 1;
@@ -438,50 +1899,128 @@ impl Command {
         Ok(Action::Continue)
     }
 
+    /// Translate the groups and intervals of an ed-style pattern, `\\(...\\)` and
+    /// `\\{n,m\\}`, into the syntax of the regex crate.
+    ///
+    /// The rest of the pattern already uses that syntax, so `(...)` is a group as well.
+    fn ed_pattern(pattern: &str) -> String {
+        let mut translated = String::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(c) if "(){}".contains(c) => translated.push(c),
+                    Some(c) => {
+                        translated.push('\\');
+                        translated.push(c);
+                    }
+                    None => translated.push('\\'),
+                },
+                c => translated.push(c),
+            }
+        }
+        translated
+    }
+
+    /// Translate an ed-style replacement into the syntax of the regex crate.
+    ///
+    /// `&` stands for the whole match and `\1` to `\9` for the captured groups,
+    /// while `\&` and `\\` are a literal `&` and `\`. A `$` has no special meaning.
+    fn ed_replacement(replacement: &str) -> String {
+        let mut translated = String::new();
+        let mut chars = replacement.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '&' => translated.push_str("${0}"),
+                '$' => translated.push_str("$$"),
+                '\\' => match chars.next() {
+                    Some(d) if d.is_ascii_digit() && d != '0' => {
+                        translated.push_str(&format!("${{{}}}", d))
+                    }
+                    Some('$') => translated.push_str("$$"),
+                    Some(c) => translated.push(c),
+                    None => translated.push('\\'),
+                },
+                c => translated.push(c),
+            }
+        }
+        translated
+    }
+
     fn substitute(
         ed: &mut Red,
         start: Option<Address>,
         end: Option<Address>,
         arg: Option<String>,
     ) -> Result<Action, failure::Error> {
-        let arg = match arg {
-            None => return Err(format_err!("No previous substitution")),
-            Some(arg) => arg,
-        };
+        let arg = arg.unwrap_or_default();
+        // Without a pattern and replacement, the last substitution is repeated
+        let (regex, replacement, flags) = if arg.is_empty() || arg == "/" {
+            match ed.last_subst.clone() {
+                None => return Err(format_err!("No previous substitution")),
+                Some((re, replacement, flags)) => {
+                    let regex = Regex::new(&re).map_err(|_| format_err!("Invalid pattern"))?;
+                    (regex, replacement, flags)
+                }
+            }
+        } else {
+            if &arg[0..=0] != "/" {
+                return Err(format_err!("Missing pattern delimiter"));
+            }
+            let arg = &arg[1..];
+            let regex_end = match arg.find('/') {
+                None => return Err(format_err!("Missing pattern delimiter")),
+                Some(idx) => idx,
+            };
+            let re = &arg[..regex_end];
 
-        if &arg[0..=0] != "/" {
-            return Err(format_err!("Missing pattern delimiter"));
-        }
-        let arg = &arg[1..];
-        let regex_end = match arg.find(|c| c == '/') {
-            None => return Err(format_err!("Missing pattern delimiter")),
-            Some(idx) => idx,
-        };
-        let re = &arg[..regex_end];
-        debug!("Regex: {:?}", re);
+            let mut replacement = &arg[regex_end + 1..];
+            let flags = match replacement.find('/') {
+                None => "",
+                Some(idx) => {
+                    let flags = &replacement[idx + 1..];
+                    replacement = &replacement[0..idx];
+                    flags
+                }
+            };
 
-        let mut replacement = &arg[regex_end + 1..];
-        let flags = match replacement.find(|c| c == '/') {
-            None => "",
-            Some(idx) => {
-                let flags = &replacement[idx + 1..];
-                replacement = &replacement[0..idx];
-                flags
-            }
+            let regex = Self::remember_pattern(ed, re)?;
+            (regex, replacement.to_string(), flags.to_string())
         };
+        debug!("Regex: {:?}", regex);
 
-        debug!("Replacement: {:?}", replacement);
+        let translated = Self::ed_replacement(&replacement);
+        debug!("Replacement: {:?}", translated);
         debug!("Flags: {:?}", flags);
 
-        let re = Regex::new(re).map_err(|_| format_err!("No match"))?;
         let all = flags.chars().any(|c| c == 'g');
+        // A number substitutes only that match on each line, or from it on with `g`
+        let digits: String = flags.chars().filter(|c| c.is_ascii_digit()).collect();
+        let nth = if digits.is_empty() {
+            1
+        } else {
+            match digits.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => return Err(format_err!("Invalid match number")),
+            }
+        };
+        let print = flags
+            .chars()
+            .filter_map(|c| match c {
+                'p' => Some(LineFormat::Plain),
+                'n' => Some(LineFormat::Numbered),
+                'l' => Some(LineFormat::List),
+                _ => None,
+            })
+            .next_back();
 
         let mut start = start
-            .map(|addr| Self::get_actual_line(&ed, addr))
+            .map(|addr| Self::get_actual_line(ed, addr))
             .unwrap_or_else(|| Ok(ed.current_line))?;
+        // A single address substitutes on that line only
         let end = end
-            .map(|addr| Self::get_actual_line(&ed, addr))
-            .unwrap_or_else(|| Ok(ed.current_line))?;
+            .map(|addr| Self::get_actual_line(ed, addr))
+            .unwrap_or_else(|| Ok(start))?;
 
         if start == 0 {
             return Err(format_err!("Invalid address"));
@@ -489,118 +2028,2626 @@ impl Command {
         start -= 1;
         debug!("Replacement in range: {}..{}", start, end);
 
-        let mut modified = None;
-        for (line, idx) in ed.data[start..end].iter_mut().zip(start..end) {
-            let new = if all {
-                let s = re.replace_all(line, replacement);
-                if &*s == line {
+        // Collect all changes first, so an interrupt leaves the buffer untouched
+        let mut changes = vec![];
+        let mut substitutions = 0;
+        for (line, idx) in ed.data[start..end].iter().zip(start..end) {
+            if ed.interrupted.load(Ordering::SeqCst) {
+                return Err(format_err!("Interrupted"));
+            }
+
+            let mut new = String::with_capacity(line.len());
+            let mut last = 0;
+            let mut matches = 0;
+            for (caps, n) in regex.captures_iter(line).zip(1..) {
+                if n < nth {
                     continue;
                 }
-                s.into_owned()
-            } else {
-                let s = re.replace(line, replacement);
-                if &*s == line {
-                    continue;
+                if n > nth && !all {
+                    break;
                 }
-                s.into_owned()
-            };
+                let m = caps.get(0).unwrap();
+                new.push_str(&line[last..m.start()]);
+                caps.expand(&translated, &mut new);
+                last = m.end();
+                matches += 1;
+            }
+            new.push_str(&line[last..]);
+            if matches == 0 || &new == line {
+                continue;
+            }
+
+            changes.push((idx, new));
+            substitutions += matches;
+        }
 
-            line.replace_range(.., &new);
-            modified = Some(idx + 1);
+        let lines_changed = changes.len();
+        let modified = changes.last().map(|&(idx, _)| idx + 1);
+        for (idx, new) in changes {
+            ed.data[idx] = new;
+        }
+
+        // Give a sense of scale when substituting over a range.
+        // This goes to stderr to keep the regular output unchanged.
+        if end - start > 1 && lines_changed > 0 {
+            eprintln!(
+                "{} substitution{} on {} line{}",
+                substitutions,
+                if substitutions == 1 { "" } else { "s" },
+                lines_changed,
+                if lines_changed == 1 { "" } else { "s" }
+            );
         }
 
         if let Some(idx) = modified {
+            ed.last_subst = Some((regex.as_str().to_string(), replacement, flags));
             ed.dirty = true;
             ed.set_line(idx)?;
-            Self::print(ed, None, None)
+            Self::print_current(ed, print)
         } else {
-            Err(format_err!("No match"))
+            Err(NoMatch.into())
         }
     }
 
-    fn write_range<W: Write>(
-        mut output: W,
+    fn translate(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        arg: Option<String>,
+    ) -> Result<Action, failure::Error> {
+        let arg = match arg {
+            None => return Err(format_err!("Missing translation sets")),
+            Some(arg) => arg,
+        };
+        if !arg.starts_with('/') {
+            return Err(format_err!("Missing pattern delimiter"));
+        }
+
+        let mut sets = arg[1..].splitn(3, '/');
+        let from = sets.next().unwrap_or("");
+        let to = match sets.next() {
+            None => return Err(format_err!("Missing pattern delimiter")),
+            Some(to) => to,
+        };
+        if from.chars().count() != to.chars().count() {
+            return Err(format_err!("Mismatched translation sets"));
+        }
+        let table: HashMap<char, char> = from.chars().zip(to.chars()).collect();
+
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        for line in &mut ed.data[start - 1..end] {
+            let new: String = line.chars().map(|c| *table.get(&c).unwrap_or(&c)).collect();
+            if new != *line {
+                *line = new;
+                ed.dirty = true;
+            }
+        }
+        ed.current_line = end;
+
+        Ok(Action::Continue)
+    }
+
+    fn escape(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        scheme: Escape,
+        reverse: bool,
+    ) -> Result<Action, failure::Error> {
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let mut lines = vec![];
+        for line in &ed.data[start - 1..end] {
+            if reverse {
+                lines.push(scheme.unescape(line)?);
+            } else {
+                lines.push(scheme.escape(line));
+            }
+        }
+
+        for (line, new) in ed.data[start - 1..end].iter_mut().zip(lines) {
+            if *line != new {
+                *line = new;
+                ed.dirty = true;
+            }
+        }
+        ed.current_line = end;
+
+        Ok(Action::Continue)
+    }
+
+    fn align(
         ed: &mut Red,
         start: Option<Address>,
         end: Option<Address>,
-        show_number: bool,
+        delimiter: Option<String>,
     ) -> Result<Action, failure::Error> {
         if ed.data.is_empty() {
             return Err(format_err!("Invalid address"));
         }
 
-        match (start, end) {
-            (None, None) => {
-                if show_number {
-                    write!(output, "{}\t", ed.current_line)?;
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::Numbered(1)), Some(Address::LastLine)),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+
+        let rows: Vec<Option<Vec<&str>>> = ed.data[start - 1..end]
+            .iter()
+            .map(|line| {
+                // Blank lines are kept as they are
+                if line.trim().is_empty() {
+                    return None;
+                }
+                match delimiter {
+                    None => Some(line.split_whitespace().collect()),
+                    Some(ref d) => Some(line.split(d.as_str()).map(str::trim).collect()),
+                }
+            })
+            .collect();
+
+        let mut widths: Vec<usize> = vec![];
+        for row in rows.iter().flatten() {
+            for (i, field) in row.iter().enumerate() {
+                let width = field.chars().count();
+                if i == widths.len() {
+                    widths.push(width);
+                } else if width > widths[i] {
+                    widths[i] = width;
                 }
-                writeln!(output, "{}", ed.get_line(ed.current_line).unwrap())?;
             }
+        }
 
-            (Some(start), None) => {
-                ed.current_line = Self::get_actual_line(&ed, start)?;
+        let separator = match delimiter {
+            None => "  ".to_string(),
+            Some(ref d) => format!(" {} ", d),
+        };
+        let lines: Vec<Option<String>> = rows
+            .into_iter()
+            .map(|row| {
+                row.map(|row| {
+                    let fields: Vec<String> = row
+                        .iter()
+                        .zip(&widths)
+                        .map(|(field, &width)| format!("{:width$}", field, width = width))
+                        .collect();
+                    fields.join(&separator).trim().to_string()
+                })
+            })
+            .collect();
 
-                if show_number {
-                    write!(output, "{}\t", ed.current_line)?;
+        for (line, new) in ed.data[start - 1..end].iter_mut().zip(lines) {
+            if let Some(new) = new {
+                if *line != new {
+                    *line = new;
+                    ed.dirty = true;
                 }
-                writeln!(output, "{}", ed.get_line(ed.current_line).unwrap())?;
             }
+        }
+        ed.current_line = end;
 
-            (None, Some(end)) => {
-                let end = Self::get_actual_line(&ed, end)?;
+        Ok(Action::Continue)
+    }
 
-                for line in 1..=end {
-                    if show_number {
-                        write!(output, "{}\t", line)?;
-                    }
-                    writeln!(output, "{}", ed.get_line(line).unwrap())?;
-                }
+    fn cut(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        from: Column,
+        to: Column,
+    ) -> Result<Action, failure::Error> {
+        // The span includes the last column, so it ends where the next one starts
+        let after = match to {
+            Column::Visual(n) => Column::Visual(n + 1),
+            Column::Byte(n) => Column::Byte(n + 1),
+        };
 
-                ed.current_line = end;
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let mut changes = vec![];
+        for (line, idx) in ed.data[start - 1..end].iter().zip(start - 1..) {
+            let first = from.byte_index(line, ed.tab_width)?;
+            let last = after.byte_index(line, ed.tab_width)?;
+            if first < last {
+                changes.push((idx, first..last));
             }
+        }
 
-            (Some(start), Some(end)) => {
-                let start = Self::get_actual_line(&ed, start)?;
-                let end = Self::get_actual_line(&ed, end)?;
+        if !changes.is_empty() {
+            ed.dirty = true;
+        }
+        for (idx, span) in changes {
+            ed.data[idx].replace_range(span, "");
+        }
+        ed.current_line = end;
 
-                for line in start..=end {
-                    if show_number {
-                        write!(output, "{}\t", line)?;
-                    }
-                    writeln!(output, "{}", ed.get_line(line).unwrap())?;
-                }
+        Ok(Action::Continue)
+    }
+
+    fn change_case(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        case: Case,
+        pattern: Option<String>,
+    ) -> Result<Action, failure::Error> {
+        let re = match pattern {
+            None => None,
+            Some(pattern) => {
+                let re = Self::parse_pattern(&pattern)?;
+                Some(Regex::new(re).map_err(|_| format_err!("Invalid pattern"))?)
+            }
+        };
 
-                ed.current_line = end;
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        for line in &mut ed.data[start - 1..end] {
+            let new = match re {
+                None => case.apply(line),
+                Some(ref re) => re
+                    .replace_all(line, |caps: &::regex::Captures| case.apply(&caps[0]))
+                    .into_owned(),
+            };
+            if new != *line {
+                *line = new;
+                ed.dirty = true;
             }
         }
+        ed.current_line = end;
 
         Ok(Action::Continue)
     }
 
-    fn get_actual_line(ed: &Red, addr: Address) -> Result<usize, failure::Error> {
-        use self::Address::*;
-        match addr {
-            CurrentLine => Ok(ed.current_line),
-            LastLine => Ok(ed.lines()),
-            Numbered(n) => {
-                if n > ed.lines() {
-                    return Err(format_err!("Invalid address"));
-                }
-                Ok(n)
+    fn clip(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let text = Self::format_clip(&ed.data[start - 1..end], start, ed.clip_format);
+        Self::set_clipboard(text)?;
+        ed.current_line = end;
+
+        Ok(Action::Continue)
+    }
+
+    /// Format `lines`, starting at line number `start`, for the clipboard.
+    fn format_clip(lines: &[String], start: usize, format: ClipFormat) -> String {
+        let mut text = String::new();
+        if format == ClipFormat::Markdown {
+            text.push_str("```\n");
+        }
+        for (line, idx) in lines.iter().zip(start..) {
+            if format == ClipFormat::Numbered {
+                text.push_str(&format!("{}\t", idx));
             }
-            Offset(n) => {
-                let line = ed.current_line as isize + n;
-                if line < 1 {
-                    return Err(format_err!("Invalid address"));
-                }
+            text.push_str(line);
+            text.push('\n');
+        }
+        if format == ClipFormat::Markdown {
+            text.push_str("```\n");
+        }
+        text
+    }
 
-                let line = line as usize;
-                if line > ed.lines() {
-                    return Err(format_err!("Invalid address"));
+    #[cfg(feature = "clipboard")]
+    fn set_clipboard(text: String) -> Result<(), failure::Error> {
+        let mut clipboard =
+            arboard::Clipboard::new().map_err(|e| format_err!("Clipboard not available: {}", e))?;
+        clipboard
+            .set_text(text)
+            .map_err(|e| format_err!("Can't write to clipboard: {}", e))?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    fn set_clipboard(_text: String) -> Result<(), failure::Error> {
+        Err(format_err!("Clipboard support not available"))
+    }
+
+    fn shell(ed: &mut Red, command: &str) -> Result<Action, failure::Error> {
+        let command = Self::expand_shell_command(ed, command)?;
+        // Output of the command goes straight to the terminal
+        Self::shell_command(&command)
+            .status()
+            .map_err(|_| format_err!("Cannot run shell command"))?;
+        println!("!");
+        Ok(Action::Continue)
+    }
+
+    /// Replace the addressed lines with the output of `command` run on them, as in `1,5!sort`.
+    ///
+    /// If the command fails, the buffer is left as it is.
+    fn filter(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        command: &str,
+    ) -> Result<Action, failure::Error> {
+        let command = Self::expand_shell_command(ed, command)?;
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let mut input = String::new();
+        for line in &ed.data[start - 1..end] {
+            input.push_str(line);
+            input.push('\n');
+        }
+
+        let mut child = Self::shell_command(&command)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .map_err(|_| format_err!("Cannot run shell command"))?;
+        // Feed the input from another thread, so a command writing a lot
+        // before reading all of it doesn't block
+        let writer = child.stdin.take().map(|mut stdin| {
+            thread::spawn(move || {
+                let _ = stdin.write_all(input.as_bytes());
+            })
+        });
+        let output = child
+            .wait_with_output()
+            .map_err(|_| format_err!("Cannot run shell command"))?;
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+        if !output.status.success() {
+            return Err(format_err!("Shell command failed"));
+        }
+        let output = String::from_utf8(output.stdout)
+            .map_err(|_| format_err!("Invalid UTF-8 in command output"))?;
+
+        let lines: Vec<String> = output.lines().map(String::from).collect();
+        let inserted = lines.len();
+        ed.lines_replaced(start - 1, end - start + 1, inserted);
+        ed.data.splice(start - 1..end, lines);
+        ed.current_line = cmp::min(start - 1 + inserted, ed.lines());
+        if inserted == 0 && ed.current_line == 0 && ed.lines() > 0 {
+            ed.current_line = 1;
+        }
+        ed.dirty = true;
+        Ok(Action::Continue)
+    }
+
+    /// Run `command` with the shell, writing the addressed lines to its standard input.
+    fn write_command(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        command: &str,
+    ) -> Result<Action, failure::Error> {
+        let command = Self::expand_shell_command(ed, command)?;
+        let (start, end) = Self::write_bounds(ed, start, end)?;
+        let mut input = vec![];
+        Self::write_lines(&mut input, ed, start, end)?;
+
+        let mut child = Self::shell_command(&command)
+            .stdin(process::Stdio::piped())
+            .spawn()
+            .map_err(|_| format_err!("Cannot run shell command"))?;
+        // A command that doesn't read all of its input is fine
+        if let Some(mut stdin) = child.stdin.take() {
+            match stdin.write_all(&input) {
+                Err(ref err) if err.kind() != io::ErrorKind::BrokenPipe => {
+                    return Err(format_err!("Cannot write to shell command"));
                 }
+                _ => {}
+            }
+        }
+        child.wait()?;
+
+        if !ed.silent {
+            println!("{}", input.len());
+        }
+        Ok(Action::Continue)
+    }
+
+    /// Run `command` with the shell and collect the lines it writes to standard output.
+    ///
+    /// Its exit status is ignored, whatever it wrote is kept.
+    fn command_output(ed: &mut Red, command: &str) -> Result<Vec<String>, failure::Error> {
+        let command = Self::expand_shell_command(ed, command)?;
+        let output = Self::shell_command(&command)
+            .stderr(process::Stdio::inherit())
+            .output()
+            .map_err(|_| format_err!("Cannot run shell command"))?;
+        let output = String::from_utf8(output.stdout)
+            .map_err(|_| format_err!("Invalid UTF-8 in command output"))?;
+        Ok(output.lines().map(String::from).collect())
+    }
+
+    /// Expand a shell command like ed: a leading `!` stands for the last command run,
+    /// an unescaped `%` for the current filename.
+    ///
+    /// The command is printed if anything was expanded, and remembered for the next `!`.
+    fn expand_shell_command(ed: &mut Red, command: &str) -> Result<String, failure::Error> {
+        let (mut expanded, rest) = match command.strip_prefix('!') {
+            Some(rest) => match ed.last_shell {
+                None => return Err(format_err!("No previous command")),
+                Some(ref last) => (last.clone(), rest),
+            },
+            None => (String::new(), command),
+        };
+        let mut changed = command.starts_with('!');
 
-                Ok(line)
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('%') => expanded.push('%'),
+                    Some(c) => {
+                        expanded.push('\\');
+                        expanded.push(c);
+                    }
+                    None => expanded.push('\\'),
+                },
+                '%' => match ed.path {
+                    None => return Err(format_err!("No current filename")),
+                    Some(ref path) => {
+                        expanded.push_str(path);
+                        changed = true;
+                    }
+                },
+                c => expanded.push(c),
             }
         }
+
+        if changed {
+            println!("{}", expanded);
+        }
+        ed.last_shell = Some(expanded.clone());
+        Ok(expanded)
+    }
+
+    /// Build a process running `command` with the user's shell, or `/bin/sh` if unset.
+    fn shell_command(command: &str) -> process::Command {
+        let shell = env::var("SHELL").unwrap_or_else(|_| "/bin/sh".into());
+        let mut child = process::Command::new(shell);
+        child.arg("-c").arg(command);
+        child
+    }
+
+    fn check(ed: &mut Red) -> Result<Action, failure::Error> {
+        let format = ed
+            .check_format
+            .or_else(|| ed.path.as_ref().and_then(|p| CheckFormat::from_path(p)));
+        let format = match format {
+            None => return Err(format_err!("Unknown format")),
+            Some(format) => format,
+        };
+
+        match format.check(&Self::join_lines(&ed.data)) {
+            Ok(()) => println!("ok"),
+            Err(e) => println!("{}", e),
+        }
+        Ok(Action::Continue)
+    }
+
+    fn yank(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        register: char,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let lines = ed.data[start - 1..end].to_vec();
+        ed.registers.insert(register, lines);
+        Ok(Action::Continue)
+    }
+
+    fn put(
+        ed: &mut Red,
+        after: Option<Address>,
+        register: char,
+        pattern: Option<String>,
+    ) -> Result<Action, failure::Error> {
+        let mut addr = after
+            .map(|addr| Self::get_actual_line(ed, addr))
+            .unwrap_or_else(|| Ok(ed.current_line))?;
+
+        let lines = match ed.registers.get(&register) {
+            None => return Err(format_err!("Nothing to put")),
+            Some(lines) => lines.clone(),
+        };
+
+        // Optionally only put the lines matching a pattern
+        let lines = match pattern {
+            None => lines,
+            Some(pattern) => {
+                let re = Self::parse_pattern(&pattern)?;
+                let re = Regex::new(re).map_err(|_| format_err!("Invalid pattern"))?;
+                let lines: Vec<String> = lines.into_iter().filter(|l| re.is_match(l)).collect();
+                if lines.is_empty() {
+                    return Err(format_err!("No match"));
+                }
+                lines
+            }
+        };
+
+        ed.lines_replaced(addr, 0, lines.len());
+        for line in lines {
+            ed.data.insert(addr, line);
+            addr += 1;
+        }
+        ed.current_line = addr;
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    fn split(ed: &mut Red, position: Position) -> Result<Action, failure::Error> {
+        let (line, idx) = Self::get_actual_position(ed, position)?;
+        let rest = ed.data[line - 1].split_off(idx);
+        ed.data.insert(line, rest);
+        ed.lines_replaced(line, 0, 1);
+        ed.current_line = line + 1;
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    fn insert_at(ed: &mut Red, position: Position, text: String) -> Result<Action, failure::Error> {
+        let (line, idx) = Self::get_actual_position(ed, position)?;
+        if !text.is_empty() {
+            ed.data[line - 1].insert_str(idx, &text);
+            ed.dirty = true;
+        }
+        ed.current_line = line;
+
+        Ok(Action::Continue)
+    }
+
+    fn compress(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        squeeze: bool,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::Numbered(1)), Some(Address::LastLine)),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+
+        let lines: Vec<String> = ed.data.drain(start - 1..end).collect();
+        let total = lines.len();
+        let mut kept = vec![];
+        for line in lines {
+            let blank = line.trim().is_empty();
+            // When squeezing, keep the first blank line of a run, emptied
+            if !blank {
+                kept.push(line);
+            } else if squeeze && kept.last().map(|l: &String| !l.is_empty()).unwrap_or(true) {
+                kept.push(String::new());
+            } else {
+                ed.lines_replaced(start - 1 + kept.len(), 1, 0);
+            }
+        }
+
+        let removed = total - kept.len();
+        let last = start - 1 + kept.len();
+        ed.data.splice(start - 1..start - 1, kept);
+
+        if removed > 0 {
+            ed.dirty = true;
+        }
+        ed.current_line = cmp::min(cmp::max(last, 1), ed.lines());
+        println!("{}", removed);
+
+        Ok(Action::Continue)
+    }
+
+    fn insert_every(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        interval: usize,
+        text: String,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::Numbered(1)), Some(Address::LastLine)),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+
+        let lines: Vec<String> = ed.data.drain(start - 1..end).collect();
+        let mut grouped = vec![];
+        let mut inserted = 0;
+        for (i, line) in lines.into_iter().enumerate() {
+            grouped.push(line);
+            if (i + 1) % interval == 0 {
+                ed.lines_replaced(start - 1 + grouped.len(), 0, 1);
+                grouped.push(text.clone());
+                inserted += 1;
+            }
+        }
+
+        ed.current_line = start - 1 + grouped.len();
+        ed.data.splice(start - 1..start - 1, grouped);
+        if inserted > 0 {
+            ed.dirty = true;
+        }
+        println!("{}", inserted);
+
+        Ok(Action::Continue)
+    }
+
+    fn block(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let (open, close) = ed.block_style.delimiters();
+
+        // The delimiters line up with the first line, the block is indented one level deeper
+        let base: String = ed.data[start - 1..end]
+            .iter()
+            .find(|l| !l.trim().is_empty())
+            .map(|l| l.chars().take_while(|c| c.is_whitespace()).collect())
+            .unwrap_or_default();
+        let unit = if base.starts_with('\t') { "\t" } else { "    " };
+
+        for line in &mut ed.data[start - 1..end] {
+            if !line.trim().is_empty() {
+                line.insert_str(0, unit);
+            }
+        }
+        ed.data.insert(end, format!("{}{}", base, close));
+        ed.lines_replaced(end, 0, 1);
+        ed.data.insert(start - 1, format!("{}{}", base, open));
+        ed.lines_replaced(start - 1, 0, 1);
+
+        ed.current_line = end + 2;
+        ed.dirty = true;
+        Ok(Action::Continue)
+    }
+
+    fn format_json(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let text = Self::join_lines(&ed.data[start - 1..end]);
+        let value = serde_json::from_str::<serde_json::Value>(&text)
+            .map_err(|e| format_err!("Invalid JSON: {}", e))?;
+        let pretty = serde_json::to_string_pretty(&value)?;
+
+        let lines: Vec<String> = pretty.lines().map(String::from).collect();
+        ed.current_line = start - 1 + lines.len();
+        ed.lines_replaced(start - 1, end - start + 1, lines.len());
+        ed.data.splice(start - 1..end, lines);
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    fn transpose(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        other_start: Address,
+        other_end: Address,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let (other_start, other_end) =
+            Self::get_actual_range(ed, Some(other_start), Some(other_end))?;
+        if other_start <= end && start <= other_end {
+            return Err(format_err!("Invalid destination"));
+        }
+
+        let ((first_start, first_end), (second_start, second_end)) = if start < other_start {
+            ((start, end), (other_start, other_end))
+        } else {
+            ((other_start, other_end), (start, end))
+        };
+        debug!(
+            "Swapping lines {}..{} with {}..{}",
+            first_start, first_end, second_start, second_end
+        );
+
+        // Extract the later range first, so the earlier one's indices stay valid
+        let second: Vec<String> = ed.data.drain(second_start - 1..second_end).collect();
+        let first: Vec<String> = ed.data.splice(first_start - 1..first_end, second).collect();
+
+        // The earlier range moved by the difference in length of both ranges
+        let second_len = second_end - second_start + 1;
+        let first_len = first_end - first_start + 1;
+        let at = second_start + second_len - first_len - 1;
+        ed.data.splice(at..at, first);
+        // Like moving the later range before the earlier one, then that one into its place
+        ed.lines_moved(second_start - 1, second_len, first_start - 1);
+        ed.lines_moved(first_start - 1 + second_len, first_len, at);
+        ed.current_line = second_end;
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    fn interleave(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        other_start: Address,
+        other_end: Address,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let (other_start, other_end) =
+            Self::get_actual_range(ed, Some(other_start), Some(other_end))?;
+        if other_start <= end && start <= other_end {
+            return Err(format_err!("Invalid destination"));
+        }
+        debug!(
+            "Interleaving lines {}..{} with {}..{}",
+            start, end, other_start, other_end
+        );
+
+        let mut first = ed.data[start - 1..end].iter().cloned();
+        let mut second = ed.data[other_start - 1..other_end].iter().cloned();
+        let mut merged = vec![];
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => break,
+                (a, b) => merged.extend(a.into_iter().chain(b)),
+            }
+        }
+
+        // The merged lines replace the first range, the second range is consumed.
+        // Change the later range first, so the earlier one's indices stay valid.
+        let len = merged.len();
+        let other_len = other_end - other_start + 1;
+        if other_start > end {
+            ed.data.drain(other_start - 1..other_end);
+            ed.lines_replaced(other_start - 1, other_len, 0);
+            ed.data.splice(start - 1..end, merged);
+            ed.lines_replaced(start - 1, end - start + 1, len);
+            ed.current_line = start - 1 + len;
+        } else {
+            ed.data.splice(start - 1..end, merged);
+            ed.lines_replaced(start - 1, end - start + 1, len);
+            ed.data.drain(other_start - 1..other_end);
+            ed.lines_replaced(other_start - 1, other_len, 0);
+            ed.current_line = start - 1 - other_len + len;
+        }
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    fn extract(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        arg: Option<String>,
+    ) -> Result<Action, failure::Error> {
+        let arg = match arg {
+            None => return Err(format_err!("No pattern")),
+            Some(arg) => arg,
+        };
+        let re = Self::parse_pattern(&arg)?;
+        let re = Regex::new(re).map_err(|_| format_err!("Invalid pattern"))?;
+
+        // Like a global command, extraction defaults to the whole buffer
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::Numbered(1)), Some(Address::LastLine)),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        let found = Self::write_captures(handle, ed, start, end, &re)?;
+        if found == 0 {
+            return Err(format_err!("No match"));
+        }
+
+        Ok(Action::Continue)
+    }
+
+    fn rotate(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        count: isize,
+    ) -> Result<Action, failure::Error> {
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let lines = &mut ed.data[start - 1..end];
+
+        // Positive counts move the last lines to the front, negative ones the other way round
+        let n = count.rem_euclid(lines.len() as isize) as usize;
+        debug!("Rotating lines {}..{} by {}", start, end, n);
+        if n != 0 {
+            lines.rotate_right(n);
+            ed.dirty = true;
+        }
+        ed.current_line = end;
+
+        Ok(Action::Continue)
+    }
+
+    fn number_lines(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        width: Option<usize>,
+        separator: String,
+    ) -> Result<Action, failure::Error> {
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        // By default, numbers are aligned to the widest one
+        let width = width.unwrap_or_else(|| ed.display_line_number(end).to_string().len());
+
+        for line in start..=end {
+            let number = ed.display_line_number(line);
+            let text = &mut ed.data[line - 1];
+            *text = format!("{:>width$}{}{}", number, separator, text, width = width);
+        }
+        ed.current_line = end;
+        ed.dirty = true;
+
+        Ok(Action::Continue)
+    }
+
+    fn long_lines(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        width: Option<usize>,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        // Like a global command, the check defaults to the whole buffer
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::Numbered(1)), Some(Address::LastLine)),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        let width = width.unwrap_or(ed.max_col);
+
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        Self::write_long_lines(handle, ed, start, end, width)?;
+        Ok(Action::Continue)
+    }
+
+    /// Write every line in `start..=end` that has more than `width` characters,
+    /// preceded by its line number.
+    ///
+    /// Returns the number of lines written.
+    fn write_long_lines<W: Write>(
+        mut output: W,
+        ed: &Red,
+        start: usize,
+        end: usize,
+        width: usize,
+    ) -> Result<usize, failure::Error> {
+        let mut found = 0;
+        for (line, idx) in ed.data[start - 1..end].iter().zip(start..) {
+            if line.chars().count() > width {
+                writeln!(output, "{}\t{}", idx, line)?;
+                found += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    fn diff(ed: &mut Red, file: Option<String>) -> Result<Action, failure::Error> {
+        let file = match file {
+            None => return Err(format_err!("No file to compare")),
+            Some(file) => file,
+        };
+        let other = ed.load_data(&file)?;
+        let name = ed.path.clone().unwrap_or_else(|| "(buffer)".into());
+
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        Self::write_diff(handle, &other, &ed.data, &file, &name)?;
+        Ok(Action::Continue)
+    }
+
+    fn patch(ed: &mut Red, file: Option<String>) -> Result<Action, failure::Error> {
+        let name = match ed.path {
+            None => return Err(format_err!("No current filename")),
+            Some(ref path) => path.clone(),
+        };
+        if ed.original == ed.data {
+            return Err(format_err!("No changes"));
+        }
+        let file = file.unwrap_or_else(|| format!("{}.patch", name));
+
+        let mut output = File::create(&file)?;
+        let old_name = format!("a/{}", name);
+        let new_name = format!("b/{}", name);
+        Self::write_diff(&mut output, &ed.original, &ed.data, &old_name, &new_name)?;
+        let size = fs::metadata(&file)?.len();
+        if !ed.silent {
+            println!("{}", size);
+        }
+
+        Ok(Action::Continue)
+    }
+
+    /// Write a unified diff turning `old` into `new`.
+    ///
+    /// Nothing is written if both are equal.
+    fn write_diff<W: Write>(
+        output: W,
+        old: &[String],
+        new: &[String],
+        old_name: &str,
+        new_name: &str,
+    ) -> Result<(), failure::Error> {
+        let old = Self::join_lines(old);
+        let new = Self::join_lines(new);
+        TextDiff::from_lines(&old, &new)
+            .unified_diff()
+            .header(old_name, new_name)
+            .to_writer(output)?;
+        Ok(())
+    }
+
+    fn join_lines(lines: &[String]) -> String {
+        lines.iter().map(|l| format!("{}\n", l)).collect()
+    }
+
+    fn outline(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        pattern: Option<String>,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+        let re = match pattern {
+            None => None,
+            Some(pattern) => {
+                let re = Self::parse_pattern(&pattern)?;
+                Some(Regex::new(re).map_err(|_| format_err!("Invalid pattern"))?)
+            }
+        };
+
+        let (start, end) = match (start, end) {
+            (None, None) => (Some(Address::Numbered(1)), Some(Address::LastLine)),
+            range => range,
+        };
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+        Self::write_outline(handle, ed, start, end, re.as_ref())?;
+        Ok(Action::Continue)
+    }
+
+    /// Write every unindented line in `start..=end`, preceded by its line number
+    /// and followed by the number of indented lines nested below it.
+    ///
+    /// With a pattern, the lines matching it are shown instead, followed by the number
+    /// of lines up to the next match.
+    /// Blank lines are neither shown nor counted.
+    fn write_outline<W: Write>(
+        mut output: W,
+        ed: &Red,
+        start: usize,
+        end: usize,
+        marker: Option<&Regex>,
+    ) -> Result<(), failure::Error> {
+        let mut top: Option<(usize, &str)> = None;
+        let mut children = 0;
+
+        for (line, idx) in ed.data[start - 1..end].iter().zip(start..) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let nested = match marker {
+                None => line.starts_with(char::is_whitespace),
+                Some(re) => !re.is_match(line),
+            };
+            if nested {
+                children += 1;
+                continue;
+            }
+
+            if let Some((idx, line)) = top {
+                Self::write_outline_entry(&mut output, idx, line, children)?;
+            }
+            top = Some((idx, line));
+            children = 0;
+        }
+
+        if let Some((idx, line)) = top {
+            Self::write_outline_entry(&mut output, idx, line, children)?;
+        }
+        Ok(())
+    }
+
+    fn write_outline_entry<W: Write>(
+        output: &mut W,
+        idx: usize,
+        line: &str,
+        children: usize,
+    ) -> Result<(), failure::Error> {
+        if children > 0 {
+            writeln!(output, "{}\t{} (+{})", idx, line, children)?;
+        } else {
+            writeln!(output, "{}\t{}", idx, line)?;
+        }
+        Ok(())
+    }
+
+    /// Write the first capture group of every matching line in `start..=end`.
+    ///
+    /// Lines that don't match, or where the group didn't participate, are skipped.
+    /// Returns the number of lines written.
+    fn write_captures<W: Write>(
+        mut output: W,
+        ed: &Red,
+        start: usize,
+        end: usize,
+        re: &Regex,
+    ) -> Result<usize, failure::Error> {
+        let mut found = 0;
+        for line in &ed.data[start - 1..end] {
+            let group = re.captures(line).and_then(|caps| caps.get(1));
+            if let Some(group) = group {
+                writeln!(output, "{}", group.as_str())?;
+                found += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Get the pattern out of an argument of the form `/RE/`.
+    ///
+    /// The closing delimiter is optional.
+    fn parse_pattern(arg: &str) -> Result<&str, failure::Error> {
+        if !arg.starts_with('/') {
+            return Err(format_err!("Missing pattern delimiter"));
+        }
+        let arg = &arg[1..];
+        match arg.find('/') {
+            None => Ok(arg),
+            Some(idx) => Ok(&arg[..idx]),
+        }
+    }
+
+    /// Print a range of lines, to the editor's `output` if it collects them.
+    fn print_range(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        format: LineFormat,
+    ) -> Result<Action, failure::Error> {
+        match ed.output.take() {
+            Some(mut output) => {
+                let result = Self::write_range(&mut output, ed, start, end, format);
+                ed.output = Some(output);
+                result
+            }
+            None => {
+                let stdout = io::stdout();
+                let handle = stdout.lock();
+                Self::write_range(handle, ed, start, end, format)
+            }
+        }
+    }
+
+    fn write_range<W: Write>(
+        mut output: W,
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+        format: LineFormat,
+    ) -> Result<Action, failure::Error> {
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let (start, end) = Self::get_actual_range(ed, start, end)?;
+        // Line numbers of a snippet are aligned to the widest one
+        let width = ed.display_line_number(end).to_string().len();
+        let markers = if format == LineFormat::Changes {
+            Self::change_markers(&ed.original, &ed.data)
+        } else {
+            vec![]
+        };
+
+        for line in start..=end {
+            let text = ed.get_line(line).unwrap();
+            let number = ed.display_line_number(line);
+            match format {
+                LineFormat::Plain => match ed.soft_wrap {
+                    None => writeln!(output, "{}", text)?,
+                    Some(width) => {
+                        for piece in Self::soft_wrap(text, width) {
+                            writeln!(output, "{}", piece)?;
+                        }
+                    }
+                },
+                LineFormat::Numbered => writeln!(output, "{}\t{}", number, text)?,
+                LineFormat::Snippet => {
+                    writeln!(output, "{:>width$} | {}", number, text, width = width)?
+                }
+                LineFormat::Changes => writeln!(output, "{}{}", markers[line - 1], text)?,
+                LineFormat::List => writeln!(output, "{}", Self::list_line(text))?,
+            }
+        }
+
+        ed.current_line = end;
+        Ok(Action::Continue)
+    }
+
+    /// Resolve the lines a write command writes: all of the buffer without an address,
+    /// which is nothing at all for an empty buffer.
+    fn write_bounds(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<(usize, usize), failure::Error> {
+        if start.is_none() && end.is_none() {
+            return Ok((1, ed.lines()));
+        }
+        if ed.data.is_empty() {
+            return Err(format_err!("Invalid address"));
+        }
+        Self::get_actual_range(ed, start, end)
+    }
+
+    /// Write the lines `start..=end` to a file as they are.
+    ///
+    /// Lines end like in the file the buffer was loaded from. The last line of the buffer
+    /// goes without a newline if that file had none.
+    fn write_lines<W: Write>(
+        mut output: W,
+        ed: &mut Red,
+        start: usize,
+        end: usize,
+    ) -> Result<(), failure::Error> {
+        let ending = ed.line_ending.as_str();
+        for (text, line) in ed.data[start - 1..end].iter().zip(start..) {
+            if line == ed.lines() && !ed.final_newline {
+                write!(output, "{}", text)?;
+            } else {
+                write!(output, "{}{}", text, ending)?;
+            }
+        }
+
+        ed.current_line = end;
+        Ok(())
+    }
+
+    /// Break `line` into pieces that fit into `width` characters,
+    /// ending all but the last with a `\` to show it continues.
+    fn soft_wrap(line: &str, width: usize) -> Vec<String> {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.len() <= width || width < 2 {
+            return vec![line.to_string()];
+        }
+
+        let mut pieces: Vec<String> = chars
+            .chunks(width - 1)
+            .map(|piece| piece.iter().collect::<String>() + "\\")
+            .collect();
+        let last = pieces.last_mut().unwrap();
+        last.pop();
+        pieces
+    }
+
+    /// Escape `line` so that every character is visible, as printed by `l`.
+    ///
+    /// Backslashes and the usual control characters are written as C escapes,
+    /// other control characters as octal escapes of their bytes,
+    /// and the end of the line is marked by a `$`.
+    fn list_line(line: &str) -> String {
+        let mut listed = String::new();
+        for c in line.chars() {
+            match c {
+                '\\' => listed.push_str("\\\\"),
+                '\x07' => listed.push_str("\\a"),
+                '\x08' => listed.push_str("\\b"),
+                '\x0c' => listed.push_str("\\f"),
+                '\r' => listed.push_str("\\r"),
+                '\t' => listed.push_str("\\t"),
+                '\x0b' => listed.push_str("\\v"),
+                c if c.is_control() => {
+                    let mut bytes = [0; 4];
+                    for b in c.encode_utf8(&mut bytes).bytes() {
+                        listed.push_str(&format!("\\{:03o}", b));
+                    }
+                }
+                c => listed.push(c),
+            }
+        }
+        listed.push('$');
+        listed
+    }
+
+    /// Mark each line of `new` as unchanged (` `), added (`+`) or modified (`~`) compared to `old`.
+    fn change_markers(old: &[String], new: &[String]) -> Vec<char> {
+        let mut markers = vec![' '; new.len()];
+        for op in capture_diff_slices(Algorithm::Myers, old, new) {
+            match op {
+                DiffOp::Equal { .. } | DiffOp::Delete { .. } => {}
+                DiffOp::Insert {
+                    new_index, new_len, ..
+                } => {
+                    for marker in &mut markers[new_index..new_index + new_len] {
+                        *marker = '+';
+                    }
+                }
+                DiffOp::Replace {
+                    old_len,
+                    new_index,
+                    new_len,
+                    ..
+                } => {
+                    let lines = markers[new_index..new_index + new_len].iter_mut();
+                    for (i, marker) in lines.enumerate() {
+                        *marker = if i < old_len { '~' } else { '+' };
+                    }
+                }
+            }
+        }
+        markers
+    }
+
+    /// Resolve a position to a line number and a byte index into that line.
+    fn get_actual_position(
+        ed: &mut Red,
+        position: Position,
+    ) -> Result<(usize, usize), failure::Error> {
+        let line = Self::get_actual_line(ed, position.line)?;
+        let text = match ed.get_line(line) {
+            None => return Err(format_err!("Invalid address")),
+            Some(text) => text,
+        };
+        let idx = position.column.byte_index(text, ed.tab_width)?;
+        Ok((line, idx))
+    }
+
+    /// Resolve an address to a line that exists in the buffer.
+    ///
+    /// `get_actual_line` resolves `$` to 0 in an empty buffer, which suits commands
+    /// adding lines after an address, but not those working on the addressed line.
+    fn get_existing_line(ed: &mut Red, addr: Address) -> Result<usize, failure::Error> {
+        let line = Self::get_actual_line(ed, addr)?;
+        if line < 1 {
+            return Err(format_err!("Invalid address"));
+        }
+        Ok(line)
+    }
+
+    /// Resolve an optional range to concrete, 1-based line numbers.
+    ///
+    /// Without any address the range is the current line.
+    fn get_actual_range(
+        ed: &mut Red,
+        start: Option<Address>,
+        end: Option<Address>,
+    ) -> Result<(usize, usize), failure::Error> {
+        let (start, end) = match (start, end) {
+            (None, None) => (ed.current_line, ed.current_line),
+            (Some(start), None) => {
+                let start = Self::get_existing_line(ed, start)?;
+                (start, start)
+            }
+            (None, Some(end)) => (1, Self::get_existing_line(ed, end)?),
+            (Some(start), Some(end)) => (
+                Self::get_existing_line(ed, start)?,
+                Self::get_existing_line(ed, end)?,
+            ),
+        };
+
+        if start < 1 || start > end || end > ed.lines() {
+            return Err(format_err!("Invalid address"));
+        }
+        Ok((start, end))
+    }
+
+    /// Move `n` lines from `base`, staying within the buffer.
+    fn offset_line(ed: &Red, base: usize, n: isize) -> Result<usize, failure::Error> {
+        let line = base as isize + n;
+        if line < 1 {
+            return Err(format_err!("Invalid address"));
+        }
+
+        let line = line as usize;
+        if line > ed.lines() {
+            return Err(format_err!("Invalid address"));
+        }
+
+        Ok(line)
+    }
+
+    fn get_actual_line(ed: &mut Red, addr: Address) -> Result<usize, failure::Error> {
+        Self::resolve_line(ed, addr, ed.current_line)
+    }
+
+    /// Resolve an address to a line number, with `current` taking the place of the current line.
+    fn resolve_line(ed: &mut Red, addr: Address, current: usize) -> Result<usize, failure::Error> {
+        use self::Address::*;
+        match addr {
+            CurrentLine => Ok(current),
+            LastLine => Ok(ed.lines()),
+            Numbered(n) => {
+                if n > ed.lines() {
+                    return Err(format_err!("Invalid address"));
+                }
+                Ok(n)
+            }
+            Offset(n) => Self::offset_line(ed, current, n),
+            Mark(label, n) => match ed.marks.get(&label) {
+                None => Err(format_err!("Invalid mark")),
+                Some(&line) => Self::offset_line(ed, line, n),
+            },
+            Expr(base, n) => {
+                let line = Self::resolve_line(ed, *base, current)?;
+                Self::offset_line(ed, line, n)
+            }
+            Relative(first, second) => {
+                let line = Self::resolve_line(ed, *first, current)?;
+                Self::resolve_line(ed, *second, line)
+            }
+            BlockEnd => Self::block_end(ed, current),
+            ForwardSearch(pattern) => Self::search(ed, &pattern, true, current),
+            BackwardSearch(pattern) => Self::search(ed, &pattern, false, current),
+        }
+    }
+
+    /// Find the last line of the block indented below the line `current`:
+    /// the following lines that are indented deeper, along with blank lines between them.
+    fn block_end(ed: &Red, current: usize) -> Result<usize, failure::Error> {
+        let indent = match ed.get_line(current) {
+            None => return Err(format_err!("Invalid address")),
+            Some(line) => Self::indent_width(line, ed.tab_width),
+        };
+
+        let mut end = current;
+        for (line, idx) in ed.data[current..].iter().zip(current + 1..) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if Self::indent_width(line, ed.tab_width) <= indent {
+                break;
+            }
+            end = idx;
+        }
+        Ok(end)
+    }
+
+    /// The visual width of the leading whitespace of `line`.
+    fn indent_width(line: &str, tab_width: usize) -> usize {
+        let mut width = 0;
+        for c in line.chars().take_while(|c| c.is_whitespace()) {
+            if c == '\t' {
+                width += tab_width - width % tab_width;
+            } else {
+                width += 1;
+            }
+        }
+        width
+    }
+
+    /// Compile `pattern` and remember it for later searches and substitutions.
+    /// An empty pattern stands for the one used last.
+    ///
+    /// A valid pattern is remembered even if it doesn't match, like in ed.
+    fn remember_pattern(ed: &mut Red, pattern: &str) -> Result<Regex, failure::Error> {
+        let pattern = if pattern.is_empty() {
+            match ed.last_pattern {
+                None => return Err(format_err!("No previous pattern")),
+                Some(ref last) => last.clone(),
+            }
+        } else {
+            Self::ed_pattern(pattern)
+        };
+        let regex = Regex::new(&pattern).map_err(|_| format_err!("Invalid pattern"))?;
+        ed.last_pattern = Some(pattern);
+        Ok(regex)
+    }
+
+    /// Find the next or previous line matching `pattern`, starting after the line `current`.
+    /// The search wraps around and ends with `current`.
+    fn search(
+        ed: &mut Red,
+        pattern: &str,
+        forward: bool,
+        current: usize,
+    ) -> Result<usize, failure::Error> {
+        let re = Self::remember_pattern(ed, pattern)?;
+        let lines = ed.lines();
+        for i in 1..=lines {
+            let line = if forward {
+                (current + i - 1) % lines + 1
+            } else {
+                (current + lines - i + lines - 1) % lines + 1
+            };
+            if re.is_match(&ed.data[line - 1]) {
+                return Ok(line);
+            }
+        }
+        Err(format_err!("No match"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn buffer(lines: &[&str]) -> Red {
+        let mut ed = Red::new("".into(), None);
+        ed.data = lines.iter().map(|l| l.to_string()).collect();
+        ed.current_line = ed.lines();
+        ed
+    }
+
+    #[test]
+    fn extract_first_group() {
+        let ed = buffer(&["id 12", "no digits", "id 7 and 8"]);
+        let re = Regex::new(r"(\d+)").unwrap();
+
+        let mut out = vec![];
+        let found = Command::write_captures(&mut out, &ed, 1, 3, &re).unwrap();
+        assert_eq!(2, found);
+        assert_eq!("12\n7\n", String::from_utf8(out).unwrap());
+        assert_eq!(3, ed.lines());
+    }
+
+    #[test]
+    fn extract_skips_missing_group() {
+        let ed = buffer(&["ab", "b"]);
+        let re = Regex::new(r"(a)?b").unwrap();
+
+        let mut out = vec![];
+        let found = Command::write_captures(&mut out, &ed, 1, 2, &re).unwrap();
+        assert_eq!(1, found);
+        assert_eq!("a\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn snippet_alignment() {
+        let mut ed = buffer(&["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"]);
+
+        let mut out = vec![];
+        let start = Some(Address::Numbered(9));
+        let end = Some(Address::Numbered(10));
+        Command::write_range(&mut out, &mut ed, start, end, LineFormat::Snippet).unwrap();
+        assert_eq!(" 9 | i\n10 | j\n", String::from_utf8(out).unwrap());
+        assert_eq!(10, ed.current_line);
+    }
+
+    #[test]
+    fn long_lines() {
+        let ed = buffer(&["short", "this is long", "äöüäöü", "1234567"]);
+
+        let mut out = vec![];
+        let found = Command::write_long_lines(&mut out, &ed, 1, 4, 6).unwrap();
+        assert_eq!(2, found);
+        assert_eq!(
+            "2\tthis is long\n4\t1234567\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn unified_diff() {
+        let old = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let new = vec!["a".to_string(), "B".to_string(), "c".to_string()];
+
+        let mut out = vec![];
+        Command::write_diff(&mut out, &old, &new, "old.txt", "new.txt").unwrap();
+        let expected = "--- old.txt\n+++ new.txt\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+
+        let mut out = vec![];
+        Command::write_diff(&mut out, &old, &old, "old.txt", "new.txt").unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn outline() {
+        let ed = buffer(&["a:", "  b: 1", "  c:", "    d: 2", "", "e: 3", "f:", "\tg"]);
+
+        let mut out = vec![];
+        Command::write_outline(&mut out, &ed, 1, 8, None).unwrap();
+        let expected = "1\ta: (+3)\n6\te: 3\n7\tf: (+1)\n";
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn outline_by_marker() {
+        let ed = buffer(&[
+            "intro",
+            "=== One ===",
+            "a",
+            "",
+            "b",
+            "=== Two ===",
+            "=== Three ===",
+            "c",
+        ]);
+        let re = Regex::new("^=== .* ===$").unwrap();
+
+        let mut out = vec![];
+        Command::write_outline(&mut out, &ed, 1, 8, Some(&re)).unwrap();
+        let expected = "2\t=== One === (+2)\n6\t=== Two ===\n7\t=== Three === (+1)\n";
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn clip_formats() {
+        let lines = vec!["fn main() {".to_string(), "}".to_string()];
+
+        assert_eq!(
+            "fn main() {\n}\n",
+            Command::format_clip(&lines, 9, ClipFormat::Raw)
+        );
+        assert_eq!(
+            "9\tfn main() {\n10\t}\n",
+            Command::format_clip(&lines, 9, ClipFormat::Numbered)
+        );
+        assert_eq!(
+            "```\nfn main() {\n}\n```\n",
+            Command::format_clip(&lines, 9, ClipFormat::Markdown)
+        );
+        assert!("fence".parse::<ClipFormat>().is_err());
+    }
+
+    #[test]
+    fn check_formats() {
+        assert_eq!(Ok(()), CheckFormat::Json.check("{\"a\": [1, 2]}"));
+        let err = CheckFormat::Json.check("{\n\"a\": [1, 2\n}").unwrap_err();
+        assert!(err.contains("line 3"), "{}", err);
+
+        assert_eq!(Ok(()), CheckFormat::Toml.check("[a]\nb = 1\n"));
+        let err = CheckFormat::Toml.check("[a]\nb = \n").unwrap_err();
+        assert!(err.contains("line 2"), "{}", err);
+
+        assert_eq!(Ok(()), CheckFormat::Yaml.check("a:\n  - b\n"));
+        assert!(CheckFormat::Yaml.check("a: [b\n").is_err());
+
+        assert_eq!(Some(CheckFormat::Yaml), CheckFormat::from_path("conf.yml"));
+        assert_eq!(
+            Some(CheckFormat::Toml),
+            CheckFormat::from_path("Cargo.TOML")
+        );
+        assert_eq!(None, CheckFormat::from_path("README"));
+    }
+
+    #[test]
+    fn visual_and_byte_columns() {
+        assert_eq!(0, Column::Visual(1).byte_index("abc", 8).unwrap());
+        assert_eq!(2, Column::Visual(3).byte_index("abc", 8).unwrap());
+        assert_eq!(3, Column::Visual(10).byte_index("abc", 8).unwrap());
+
+        // The tab covers columns 2 to 4
+        assert_eq!(1, Column::Visual(2).byte_index("a\tbc", 4).unwrap());
+        assert_eq!(1, Column::Visual(4).byte_index("a\tbc", 4).unwrap());
+        assert_eq!(2, Column::Visual(5).byte_index("a\tbc", 4).unwrap());
+        assert_eq!(2, Column::Byte(3).byte_index("a\tbc", 4).unwrap());
+
+        assert_eq!(2, Column::Visual(2).byte_index("äb", 8).unwrap());
+        assert!(Column::Byte(2).byte_index("äb", 8).is_err());
+    }
+
+    #[test]
+    fn numbered_with_line_offset() {
+        let mut ed = buffer(&["a", "b", "c"]);
+        ed.line_offset = 99;
+
+        let mut out = vec![];
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::Numbered(2));
+        Command::write_range(
+            &mut out,
+            &mut ed,
+            start.clone(),
+            end.clone(),
+            LineFormat::Numbered,
+        )
+        .unwrap();
+        assert_eq!("99\ta\n100\tb\n", String::from_utf8(out).unwrap());
+
+        let mut out = vec![];
+        Command::write_range(&mut out, &mut ed, start, end, LineFormat::Snippet).unwrap();
+        assert_eq!(" 99 | a\n100 | b\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn soft_wrapped_print() {
+        let mut ed = buffer(&["short", "a line too long to fit"]);
+
+        let mut out = vec![];
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::LastLine);
+        Command::write_range(&mut out, &mut ed, start, end, LineFormat::Plain).unwrap();
+        assert_eq!(
+            "short\na line too long to fit\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        ed.soft_wrap = Some(8);
+        let mut out = vec![];
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::LastLine);
+        Command::write_range(&mut out, &mut ed, start, end, LineFormat::Plain).unwrap();
+        assert_eq!(
+            "short\na line \\\ntoo lon\\\ng to fi\\\nt\n",
+            String::from_utf8(out).unwrap()
+        );
+        // The buffer itself is unchanged
+        assert_eq!("a line too long to fit", ed.data[1]);
+
+        Command::toggle_soft_wrap(&mut ed).unwrap();
+        assert_eq!(None, ed.soft_wrap);
+        Command::toggle_soft_wrap(&mut ed).unwrap();
+        assert!(ed.soft_wrap.is_some());
+    }
+
+    #[test]
+    fn list_escapes() {
+        let mut ed = buffer(&["a\tb\\c", "bell\x07\x08\x1b\x7f\r", "", "$ü"]);
+
+        let mut out = vec![];
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::LastLine);
+        Command::write_range(&mut out, &mut ed, start, end, LineFormat::List).unwrap();
+        assert_eq!(
+            "a\\tb\\\\c$\nbell\\a\\b\\033\\177\\r$\n$\n$ü$\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn changes_since_load() {
+        let mut ed = buffer(&["a", "B", "c", "new", "d"]);
+        ed.original = vec!["a", "b", "c", "d", "e"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let mut out = vec![];
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::LastLine);
+        Command::write_range(&mut out, &mut ed, start, end, LineFormat::Changes).unwrap();
+        assert_eq!(" a\n~B\n c\n+new\n d\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn evaluate_expression() {
+        let mut ed = buffer(&["(1 + 2) * 4", "7 / 2.0", "two plus two"]);
+
+        Command::evaluate(&mut ed, Some(Address::Numbered(1))).unwrap();
+        assert_eq!(
+            vec!["(1 + 2) * 4", "= 12", "7 / 2.0", "two plus two"],
+            ed.data
+        );
+        assert_eq!(2, ed.current_line);
+
+        Command::evaluate(&mut ed, Some(Address::Numbered(3))).unwrap();
+        assert_eq!("= 3.5", ed.data[3]);
+
+        ed.dirty = false;
+        assert!(Command::evaluate(&mut ed, Some(Address::LastLine)).is_err());
+        assert_eq!(5, ed.lines());
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn mark_offsets() {
+        let mut ed = buffer(&["a", "b", "c", "d", "e"]);
+        Command::mark(&mut ed, Some(Address::Numbered(2)), 'a').unwrap();
+        ed.current_line = 5;
+
+        let range = Command::get_actual_range(
+            &mut ed,
+            Some(Address::Mark('a', 1)),
+            Some(Address::Mark('a', 3)),
+        );
+        assert_eq!((3, 5), range.unwrap());
+        assert_eq!(
+            2,
+            Command::get_actual_line(&mut ed, Address::Mark('a', 0)).unwrap()
+        );
+
+        assert!(Command::get_actual_line(&mut ed, Address::Mark('a', 4)).is_err());
+        assert!(Command::get_actual_line(&mut ed, Address::Mark('a', -2)).is_err());
+        assert!(Command::get_actual_line(&mut ed, Address::Mark('b', 0)).is_err());
+    }
+
+    #[test]
+    fn transpose_ranges() {
+        let mut ed = buffer(&["1", "2", "3", "4", "5", "6"]);
+        Command::transpose(
+            &mut ed,
+            Some(Address::Numbered(1)),
+            Some(Address::Numbered(2)),
+            Address::Numbered(4),
+            Address::Numbered(6),
+        )
+        .unwrap();
+        assert_eq!(vec!["4", "5", "6", "3", "1", "2"], ed.data);
+        assert_eq!(6, ed.current_line);
+        assert!(ed.dirty);
+
+        // The order of the ranges does not matter
+        let mut ed = buffer(&["1", "2", "3", "4", "5", "6"]);
+        Command::transpose(
+            &mut ed,
+            Some(Address::Numbered(5)),
+            None,
+            Address::Numbered(1),
+            Address::Numbered(3),
+        )
+        .unwrap();
+        assert_eq!(vec!["5", "4", "1", "2", "3", "6"], ed.data);
+        assert_eq!(5, ed.current_line);
+
+        let mut ed = buffer(&["1", "2", "3", "4"]);
+        let overlap = Command::transpose(
+            &mut ed,
+            Some(Address::Numbered(1)),
+            Some(Address::Numbered(3)),
+            Address::Numbered(3),
+            Address::Numbered(4),
+        );
+        assert!(overlap.is_err());
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn format_json() {
+        let mut ed = buffer(&["before", r#"{"b": [1, 2],"#, r#""a": null}"#, "after"]);
+        Command::format_json(
+            &mut ed,
+            Some(Address::Numbered(2)),
+            Some(Address::Numbered(3)),
+        )
+        .unwrap();
+
+        let expected = vec![
+            "before",
+            "{",
+            r#"  "b": ["#,
+            "    1,",
+            "    2",
+            "  ],",
+            r#"  "a": null"#,
+            "}",
+            "after",
+        ];
+        assert_eq!(expected, ed.data);
+        assert_eq!(8, ed.current_line);
+        assert!(ed.dirty);
+
+        let mut ed = buffer(&["{", "oops"]);
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::LastLine);
+        assert!(Command::format_json(&mut ed, start, end).is_err());
+        assert_eq!(vec!["{", "oops"], ed.data);
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn change_case() {
+        let mut ed = buffer(&[
+            "the QUICK brown fox",
+            "don't stop-me now",
+            "ÄRGER über straße",
+        ]);
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::LastLine);
+
+        Command::change_case(&mut ed, start.clone(), end.clone(), Case::Title, None).unwrap();
+        assert_eq!(
+            vec![
+                "The Quick Brown Fox",
+                "Don't Stop-Me Now",
+                "Ärger Über Straße"
+            ],
+            ed.data
+        );
+        assert_eq!(3, ed.current_line);
+        assert!(ed.dirty);
+
+        Command::change_case(&mut ed, start, end, Case::Upper, Some("/o\\w/".into())).unwrap();
+        assert_eq!(
+            vec![
+                "The Quick BrOWn FOX",
+                "DON't StOP-Me NOW",
+                "Ärger Über Straße"
+            ],
+            ed.data
+        );
+
+        ed.dirty = false;
+        Command::change_case(
+            &mut ed,
+            Some(Address::Numbered(3)),
+            None,
+            Case::Upper,
+            Some("/x/".into()),
+        )
+        .unwrap();
+        assert!(!ed.dirty);
+
+        Command::change_case(&mut ed, Some(Address::Numbered(3)), None, Case::Lower, None).unwrap();
+        assert_eq!("ärger über straße", ed.data[2]);
+    }
+
+    #[test]
+    fn ruler() {
+        let mut ed = buffer(&["a", "b"]);
+        Command::ruler(&mut ed, Some(Address::Numbered(1)), '-', Some(5)).unwrap();
+        assert_eq!(vec!["a", "-----", "b"], ed.data);
+        assert_eq!(2, ed.current_line);
+        assert!(ed.dirty);
+
+        Command::ruler(&mut ed, Some(Address::Numbered(0)), '=', Some(3)).unwrap();
+        assert_eq!(vec!["===", "a", "-----", "b"], ed.data);
+        assert_eq!(1, ed.current_line);
+    }
+
+    #[test]
+    fn cut_columns() {
+        let mut ed = buffer(&["abcdefgh", "abc", "", "a\tbcdef", "äöüßx"]);
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::LastLine);
+        Command::cut(&mut ed, start, end, Column::Visual(2), Column::Visual(4)).unwrap();
+        assert_eq!(vec!["aefgh", "a", "", "a\tbcdef", "äx"], ed.data);
+        assert_eq!(5, ed.current_line);
+        assert!(ed.dirty);
+
+        let mut ed = buffer(&["a\tbcdef"]);
+        ed.tab_width = 4;
+        Command::cut(&mut ed, None, None, Column::Visual(2), Column::Visual(5)).unwrap();
+        assert_eq!(vec!["acdef"], ed.data);
+
+        let mut ed = buffer(&["äbc"]);
+        assert!(Command::cut(&mut ed, None, None, Column::Byte(2), Column::Byte(3)).is_err());
+        assert_eq!(vec!["äbc"], ed.data);
+        assert!(!ed.dirty);
+    }
+    #[test]
+    fn escape_schemes() {
+        let line = "say \"it's\" \\ done\t\u{1}\u{7f} ü";
+
+        assert_eq!(
+            r#"say \"it's\" \\ done\t\u0001\u007f ü"#,
+            Escape::Json.escape(line)
+        );
+        assert_eq!(
+            "'say \"it'\\''s\" \\ done\t\u{1}\u{7f} ü'",
+            Escape::Shell.escape(line)
+        );
+        assert_eq!(
+            r#"say \"it's\" \\ done\t\001\177 ü"#,
+            Escape::C.escape(line)
+        );
+
+        for &scheme in &[Escape::Json, Escape::Shell, Escape::C] {
+            assert_eq!(line, scheme.unescape(&scheme.escape(line)).unwrap());
+            assert_eq!("", scheme.unescape(&scheme.escape("")).unwrap());
+        }
+
+        assert_eq!("a b$c", Escape::Shell.unescape(r#"a\ "b\$"c"#).unwrap());
+        assert_eq!("\u{1b}[0m?", Escape::C.unescape(r#"\x1b[0m\?"#).unwrap());
+        assert!(Escape::Json.unescape(r#"\q"#).is_err());
+        assert!(Escape::Shell.unescape("'open").is_err());
+        assert!(Escape::C.unescape(r#"trailing\"#).is_err());
+    }
+
+    #[test]
+    fn escape_range() {
+        let mut ed = buffer(&["plain", "a \"quote\"", "back\\slash"]);
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::LastLine);
+        Command::escape(&mut ed, start.clone(), end.clone(), Escape::C, false).unwrap();
+        assert_eq!(vec!["plain", r#"a \"quote\""#, r#"back\\slash"#], ed.data);
+        assert!(ed.dirty);
+
+        ed.data.push(r#"bad \q"#.into());
+        let result = Command::escape(&mut ed, start.clone(), end.clone(), Escape::C, true);
+        assert!(result.is_err());
+        assert_eq!(r#"a \"quote\""#, ed.data[1]);
+
+        ed.data.pop();
+        Command::escape(&mut ed, start, end, Escape::C, true).unwrap();
+        assert_eq!(vec!["plain", "a \"quote\"", "back\\slash"], ed.data);
+    }
+
+    #[test]
+    fn relative_ranges() {
+        let mut ed = buffer(&["1", "2", "3", "a", "5", "6", "a", "8"]);
+        ed.current_line = 5;
+        let relative = |first, second| Address::Relative(Box::new(first), Box::new(second));
+
+        let end = relative(Address::Numbered(1), Address::Offset(2));
+        assert_eq!(3, Command::get_actual_line(&mut ed, end).unwrap());
+        let end = relative(Address::Numbered(5), Address::ForwardSearch("a".into()));
+        assert_eq!(7, Command::get_actual_line(&mut ed, end).unwrap());
+        let end = relative(Address::Numbered(1), Address::ForwardSearch("a".into()));
+        assert_eq!(4, Command::get_actual_line(&mut ed, end).unwrap());
+        assert_eq!(5, ed.current_line);
+    }
+
+    #[test]
+    fn search_addresses() {
+        let mut ed = buffer(&["fn a", "x", "fn b", "y", "fn c"]);
+        ed.current_line = 3;
+
+        let forward = |ed: &mut Red, re: &str| {
+            Command::get_actual_line(ed, Address::ForwardSearch(re.into()))
+        };
+        let backward = |ed: &mut Red, re: &str| {
+            Command::get_actual_line(ed, Address::BackwardSearch(re.into()))
+        };
+
+        assert_eq!(5, forward(&mut ed, "^fn").unwrap());
+        assert_eq!(1, backward(&mut ed, "^fn").unwrap());
+        // Wrap around, and the current line itself is searched last
+        assert_eq!(2, forward(&mut ed, "x").unwrap());
+        assert_eq!(4, backward(&mut ed, "y").unwrap());
+        assert_eq!(3, forward(&mut ed, "b").unwrap());
+        assert_eq!(3, backward(&mut ed, "b").unwrap());
+
+        assert_eq!(Some("b".into()), ed.last_pattern);
+
+        let err = forward(&mut ed, "nowhere").unwrap_err();
+        assert_eq!("No match", err.to_string());
+        assert_eq!(Some("nowhere".into()), ed.last_pattern);
+        assert!(forward(&mut ed, "(").is_err());
+        assert_eq!(Some("nowhere".into()), ed.last_pattern);
+    }
+
+    #[test]
+    fn align_columns() {
+        let mut ed = buffer(&["name age city", "", "bob 7 Paris", "alexandra 42"]);
+        Command::align(&mut ed, None, None, None).unwrap();
+        assert_eq!(
+            vec![
+                "name       age  city",
+                "",
+                "bob        7    Paris",
+                "alexandra  42"
+            ],
+            ed.data
+        );
+        assert_eq!(4, ed.current_line);
+        assert!(ed.dirty);
+
+        let mut ed = buffer(&["|a|bb|", "|ccc| d |", "x,y"]);
+        let end = Some(Address::Numbered(2));
+        Command::align(&mut ed, Some(Address::Numbered(1)), end, Some("|".into())).unwrap();
+        assert_eq!(vec!["| a   | bb |", "| ccc | d  |", "x,y"], ed.data);
+
+        // Aligning again changes nothing
+        ed.dirty = false;
+        let end = Some(Address::Numbered(2));
+        Command::align(&mut ed, Some(Address::Numbered(1)), end, Some("|".into())).unwrap();
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn last_line_of_empty_buffer() {
+        let mut ed = buffer(&[]);
+        let last = || Some(Address::LastLine);
+
+        let err = Command::Print {
+            start: last(),
+            end: None,
+        }
+        .execute(&mut ed)
+        .unwrap_err();
+        assert_eq!("Invalid address", err.to_string());
+        let err = Command::Delete {
+            start: last(),
+            end: None,
+            print: None,
+        }
+        .execute(&mut ed)
+        .unwrap_err();
+        assert_eq!("Invalid address", err.to_string());
+
+        // `$=` prints 0 like ed
+        Command::LineNumber { address: last() }
+            .execute(&mut ed)
+            .unwrap();
+
+        // Appending after `$` creates the first line
+        Command::Append {
+            after: last(),
+            print: None,
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert_eq!(Mode::Input, ed.mode);
+        assert_eq!(0, ed.current_line);
+
+        // Line 0 exists in no buffer
+        let mut ed = buffer(&["a"]);
+        let zero = Some(Address::Numbered(0));
+        assert!(Command::delete(&mut ed, zero.clone(), None).is_err());
+        assert!(Command::delete(&mut ed, None, zero.clone()).is_err());
+        assert!(Command::delete(&mut ed, zero, Some(Address::Numbered(1))).is_err());
+        assert_eq!(vec!["a"], ed.data);
+    }
+
+    #[test]
+    fn number_lines() {
+        let mut ed = buffer(&["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"]);
+        let start = Some(Address::Numbered(8));
+        let end = Some(Address::Numbered(10));
+        Command::number_lines(&mut ed, start, end, None, "\t".into()).unwrap();
+        assert_eq!(vec![" 8\th", " 9\ti", "10\tj"], &ed.data[7..10]);
+        assert_eq!(10, ed.current_line);
+        assert!(ed.dirty);
+
+        let mut ed = buffer(&["a", "b"]);
+        ed.line_offset = 41;
+        let end = Some(Address::LastLine);
+        Command::number_lines(
+            &mut ed,
+            Some(Address::Numbered(1)),
+            end,
+            Some(4),
+            ": ".into(),
+        )
+        .unwrap();
+        assert_eq!(vec!["  41: a", "  42: b"], ed.data);
+    }
+
+    #[test]
+    fn indentation_block() {
+        let mut ed = buffer(&[
+            "fn main() {",
+            "    if x {",
+            "        a();",
+            "",
+            "\tb();",
+            "    }",
+            "",
+            "}",
+        ]);
+
+        ed.current_line = 2;
+        assert_eq!(
+            5,
+            Command::get_actual_line(&mut ed, Address::BlockEnd).unwrap()
+        );
+        ed.current_line = 1;
+        assert_eq!(
+            6,
+            Command::get_actual_line(&mut ed, Address::BlockEnd).unwrap()
+        );
+        // A line without deeper indented lines below is a block of its own
+        ed.current_line = 6;
+        assert_eq!(
+            6,
+            Command::get_actual_line(&mut ed, Address::BlockEnd).unwrap()
+        );
+
+        ed.current_line = 2;
+        Command::Delete {
+            start: Some(Address::CurrentLine),
+            end: Some(Address::BlockEnd),
+            print: None,
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert_eq!(vec!["fn main() {", "    }", "", "}"], ed.data);
+    }
+
+    #[test]
+    fn delete_ranges() {
+        let mut ed = buffer(&["1", "2", "3", "4", "5"]);
+        let delete = |start, end| Command::Delete {
+            start: Some(start),
+            end: Some(end),
+            print: None,
+        };
+
+        let err = delete(Address::Numbered(5), Address::Numbered(2))
+            .execute(&mut ed)
+            .unwrap_err();
+        assert_eq!("Invalid address", err.to_string());
+        assert_eq!(5, ed.lines());
+        assert!(delete(Address::Numbered(4), Address::Numbered(6))
+            .execute(&mut ed)
+            .is_err());
+        assert_eq!(5, ed.lines());
+
+        delete(Address::Numbered(2), Address::Numbered(3))
+            .execute(&mut ed)
+            .unwrap();
+        assert_eq!(vec!["1", "4", "5"], ed.data);
+        assert_eq!(2, ed.current_line);
+
+        // Deleting the last line makes the new last line the current one
+        delete(Address::LastLine, Address::LastLine)
+            .execute(&mut ed)
+            .unwrap();
+        assert_eq!(vec!["1", "4"], ed.data);
+        assert_eq!(2, ed.current_line);
+    }
+
+    #[test]
+    fn substitute_nth_match() {
+        let mut ed = buffer(&["a-a-a", "a"]);
+        let arg = Some("/a/b/2".into());
+        Command::substitute(&mut ed, Some(Address::Numbered(1)), None, arg).unwrap();
+        assert_eq!("a-b-a", ed.data[0]);
+
+        let arg = Some("/a/(&)/2g".into());
+        Command::substitute(&mut ed, Some(Address::Numbered(1)), None, arg).unwrap();
+        assert_eq!("a-b-(a)", ed.data[0]);
+
+        // No line has a fourth match
+        let arg = Some("/[ab]/x/4".into());
+        let err = Command::substitute(
+            &mut ed,
+            Some(Address::Numbered(1)),
+            Some(Address::LastLine),
+            arg,
+        )
+        .unwrap_err();
+        assert_eq!("No match", err.to_string());
+        assert_eq!(vec!["a-b-(a)", "a"], ed.data);
+
+        let arg = Some("/a/b/0".into());
+        assert!(Command::substitute(&mut ed, None, None, arg).is_err());
+    }
+
+    #[test]
+    fn scroll_window() {
+        let lines = (1..=50).map(|n| n.to_string()).collect::<Vec<_>>();
+        let mut ed = Red::new("".into(), None);
+        ed.data = lines;
+        ed.window = Some(22);
+        ed.current_line = 1;
+
+        Command::Scroll {
+            start: None,
+            count: None,
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert_eq!(23, ed.current_line);
+        Command::Scroll {
+            start: None,
+            count: None,
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert_eq!(45, ed.current_line);
+
+        // A count is remembered, the last window stops at the end of the buffer
+        Command::Scroll {
+            start: Some(Address::Numbered(10)),
+            count: Some(5),
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert_eq!(14, ed.current_line);
+        assert_eq!(Some(5), ed.window);
+        ed.current_line = 48;
+        Command::Scroll {
+            start: None,
+            count: None,
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert_eq!(50, ed.current_line);
+
+        let err = Command::Scroll {
+            start: None,
+            count: None,
+        }
+        .execute(&mut ed)
+        .unwrap_err();
+        assert_eq!("Invalid address", err.to_string());
+    }
+
+    #[test]
+    fn print_suffix() {
+        let mut ed = buffer(&["1", "2", "3", "4"]);
+        ed.output = Some(vec![]);
+        Command::Delete {
+            start: Some(Address::Numbered(2)),
+            end: None,
+            print: Some(LineFormat::Plain),
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert_eq!(vec!["1", "3", "4"], ed.data);
+        assert_eq!(2, ed.current_line);
+        // The suffix prints the new current line, the one following the deleted line
+        assert_eq!(b"3\n", &ed.output.unwrap()[..]);
+
+        // Without a line left, there's nothing to print
+        let mut ed = buffer(&["1"]);
+        ed.output = Some(vec![]);
+        Command::Delete {
+            start: None,
+            end: None,
+            print: Some(LineFormat::Numbered),
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert!(ed.data.is_empty());
+        assert!(ed.output.unwrap().is_empty());
+    }
+
+    #[test]
+    fn ed_style_replacement() {
+        assert_eq!("${0}-${1}", Command::ed_replacement("&-\\1"));
+        assert_eq!("a&b\\c", Command::ed_replacement("a\\&b\\\\c"));
+        assert_eq!("$$1 costs", Command::ed_replacement("$1 costs"));
+
+        let mut ed = buffer(&["foobar and foobaz", "a+b"]);
+        let arg = Some("/(foo)bar/&-\\1/".into());
+        Command::substitute(&mut ed, Some(Address::Numbered(1)), None, arg).unwrap();
+        assert_eq!("foobar-foo and foobaz", ed.data[0]);
+
+        let arg = Some("/\\+/ \\& /".into());
+        Command::substitute(&mut ed, Some(Address::Numbered(2)), None, arg).unwrap();
+        assert_eq!("a & b", ed.data[1]);
+
+        let arg = Some("/o/[&]/g".into());
+        Command::substitute(&mut ed, Some(Address::Numbered(1)), None, arg).unwrap();
+        assert_eq!("f[o][o]bar-f[o][o] and f[o][o]baz", ed.data[0]);
+        // Groups and intervals can be written like in ed
+        let mut ed = buffer(&["foobar", "foo"]);
+        let arg = Some("/\\(foo\\)bar/&-\\1/".into());
+        Command::substitute(&mut ed, Some(Address::Numbered(1)), None, arg).unwrap();
+        assert_eq!("foobar-foo", ed.data[0]);
+        let arg = Some("/o\\{2\\}/0/".into());
+        Command::substitute(&mut ed, Some(Address::Numbered(2)), None, arg).unwrap();
+        assert_eq!("f0", ed.data[1]);
+        assert_eq!("(a)b{2}\\.", Command::ed_pattern("\\(a\\)b\\{2\\}\\."));
+    }
+
+    #[test]
+    fn join_lines() {
+        let mut ed = buffer(&["a", "b", "c", "d", "e"]);
+        let start = Some(Address::Numbered(2));
+        let end = Some(Address::Numbered(4));
+        Command::join(&mut ed, start, end).unwrap();
+        assert_eq!(vec!["a", "bcd", "e"], ed.data);
+        assert_eq!(2, ed.current_line);
+        assert!(ed.dirty);
+
+        ed.current_line = 1;
+        Command::join(&mut ed, None, None).unwrap();
+        assert_eq!(vec!["abcd", "e"], ed.data);
+
+        // A single line is left alone
+        ed.dirty = false;
+        Command::join(&mut ed, Some(Address::Numbered(2)), None).unwrap();
+        assert_eq!(vec!["abcd", "e"], ed.data);
+        assert!(!ed.dirty);
+
+        ed.current_line = 2;
+        assert!(Command::join(&mut ed, None, None).is_err());
+    }
+
+    #[test]
+    fn reflow_paragraph() {
+        let mut ed = buffer(&[
+            "title",
+            "  The quick brown",
+            "  fox   jumps over",
+            "    the lazy dog.",
+            "end",
+        ]);
+        ed.wrap_width = 16;
+        let start = Some(Address::Numbered(2));
+        let end = Some(Address::Numbered(4));
+        Command::reflow(&mut ed, start, end).unwrap();
+        assert_eq!(
+            vec![
+                "title",
+                "  The quick",
+                "  brown fox",
+                "  jumps over the",
+                "  lazy dog.",
+                "end",
+            ],
+            ed.data
+        );
+        assert_eq!(5, ed.current_line);
+        assert!(ed.dirty);
+
+        // Words longer than the width get a line of their own
+        let mut ed = buffer(&["a", "abcdefghij", "b c"]);
+        ed.wrap_width = 5;
+        let end = Some(Address::LastLine);
+        Command::reflow(&mut ed, Some(Address::Numbered(1)), end).unwrap();
+        assert_eq!(vec!["a", "abcdefghij", "b c"], ed.data);
+
+        // Blank lines collapse into a single empty line
+        let mut ed = buffer(&["", "   "]);
+        Command::reflow(&mut ed, Some(Address::Numbered(1)), Some(Address::LastLine)).unwrap();
+        assert_eq!(vec![""], ed.data);
+        assert_eq!(1, ed.current_line);
+    }
+
+    #[test]
+    fn transfer_lines() {
+        let mut ed = buffer(&["1", "2", "3", "4", "5"]);
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::Numbered(2));
+        Command::transfer(&mut ed, start, end, Address::Numbered(4)).unwrap();
+        assert_eq!(vec!["1", "2", "3", "4", "1", "2", "5"], ed.data);
+        assert_eq!(6, ed.current_line);
+        assert!(ed.dirty);
+
+        Command::transfer(&mut ed, None, None, Address::Numbered(0)).unwrap();
+        assert_eq!(vec!["2", "1", "2", "3", "4", "1", "2", "5"], ed.data);
+        assert_eq!(1, ed.current_line);
+
+        // Copying a range into itself
+        let mut ed = buffer(&["a", "b", "c"]);
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::Numbered(3));
+        Command::transfer(&mut ed, start, end, Address::Numbered(1)).unwrap();
+        assert_eq!(vec!["a", "a", "b", "c", "b", "c"], ed.data);
+    }
+
+    #[test]
+    fn shell_escape() {
+        let mut ed = buffer(&["a", "b"]);
+        Command::Shell {
+            command: "exit 0".into(),
+        }
+        .execute(&mut ed)
+        .unwrap();
+        // A failing command is reported by the shell, not as an error
+        Command::Shell {
+            command: "exit 3".into(),
+        }
+        .execute(&mut ed)
+        .unwrap();
+        assert_eq!(vec!["a", "b"], ed.data);
+        assert!(!ed.dirty);
+
+        let output = Command::shell_command("echo hello").output().unwrap();
+        assert_eq!("hello\n", String::from_utf8(output.stdout).unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn filter_through_command() {
+        let mut ed = buffer(&["c", "a", "b", "end"]);
+        let start = Some(Address::Numbered(1));
+        let end = Some(Address::Numbered(3));
+        Command::filter(&mut ed, start.clone(), end.clone(), "sort").unwrap();
+        assert_eq!(vec!["a", "b", "c", "end"], ed.data);
+        assert_eq!(3, ed.current_line);
+        assert!(ed.dirty);
+
+        // Output may have a different number of lines
+        Command::filter(&mut ed, start.clone(), end.clone(), "tr -d '\\n'; echo").unwrap();
+        assert_eq!(vec!["abc", "end"], ed.data);
+        assert_eq!(1, ed.current_line);
+
+        // A failing command leaves the lines alone
+        let err = Command::filter(&mut ed, start, None, "exit 1").unwrap_err();
+        assert_eq!("Shell command failed", err.to_string());
+        assert_eq!(vec!["abc", "end"], ed.data);
+    }
+
+    #[test]
+    fn expand_shell_commands() {
+        let mut ed = buffer(&[]);
+        let err = Command::expand_shell_command(&mut ed, "!").unwrap_err();
+        assert_eq!("No previous command", err.to_string());
+        let err = Command::expand_shell_command(&mut ed, "wc %").unwrap_err();
+        assert_eq!("No current filename", err.to_string());
+
+        ed.path = Some("file.txt".into());
+        let expanded = Command::expand_shell_command(&mut ed, "wc -l % \\% a\\b").unwrap();
+        assert_eq!("wc -l file.txt % a\\b", expanded);
+        assert_eq!(
+            "wc -l file.txt % a\\b",
+            Command::expand_shell_command(&mut ed, "!").unwrap()
+        );
+        assert_eq!(
+            "wc -l file.txt % a\\b | sort",
+            Command::expand_shell_command(&mut ed, "! | sort").unwrap()
+        );
+        assert_eq!(Some("wc -l file.txt % a\\b | sort".into()), ed.last_shell);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn read_command_output() {
+        let mut ed = buffer(&[]);
+        Command::read(&mut ed, None, Some("!echo hi".into())).unwrap();
+        assert_eq!(vec!["hi"], ed.data);
+        assert_eq!(1, ed.current_line);
+        assert!(ed.dirty);
+
+        // Output is kept even if the command fails
+        let command = "!printf 'a\\nb\\n'; exit 1";
+        Command::read(&mut ed, Some(Address::Numbered(0)), Some(command.into())).unwrap();
+        assert_eq!(vec!["a", "b", "hi"], ed.data);
+        assert_eq!(2, ed.current_line);
+
+        ed.dirty = false;
+        Command::read(&mut ed, None, Some("!true".into())).unwrap();
+        assert_eq!(vec!["a", "b", "hi"], ed.data);
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn write_to_command() {
+        let name = format!("red-{}-write-command-test.txt", process::id());
+        let path = env::temp_dir().join(name);
+        let path = path.to_str().unwrap().to_string();
+
+        let mut ed = buffer(&["one", "two", "three"]);
+        ed.silent = true;
+        ed.dirty = true;
+        let command = format!("!cat > {}", path);
+        Command::write(&mut ed, None, None, Some(command.clone()), false, false).unwrap();
+        assert_eq!("one\ntwo\nthree\n", fs::read_to_string(&path).unwrap());
+        assert!(ed.dirty);
+        assert_eq!(None, ed.path);
+
+        let start = Some(Address::Numbered(2));
+        Command::write(&mut ed, start, None, Some(command), false, false).unwrap();
+        assert_eq!("two\n", fs::read_to_string(&path).unwrap());
+
+        // The command doesn't have to read its input
+        Command::write(&mut ed, None, None, Some("!true".into()), false, false).unwrap();
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn edit_command_output() {
+        let mut ed = buffer(&["old"]);
+        ed.path = Some("file.txt".into());
+        ed.silent = true;
+        ed.dirty = true;
+        assert!(Command::edit(&mut ed, Some("!echo hi".into()), false).is_err());
+
+        Command::edit(&mut ed, Some("!printf 'a\nb\n'".into()), true).unwrap();
+        assert_eq!(vec!["a", "b"], ed.data);
+        assert_eq!(2, ed.current_line);
+        assert!(!ed.dirty);
+        assert_eq!(Some("file.txt".into()), ed.path);
+    }
+
+    #[test]
+    fn status_line() {
+        let mut ed = buffer(&["a", "b", "c", "d"]);
+        ed.current_line = 1;
+        assert_eq!(
+            "[no file]: line 1 of 4 (25%), saved, lf",
+            Command::status_line(&ed)
+        );
+
+        ed.path = Some("file.txt".into());
+        ed.data = vec!["a".into(), "b".into(), "c".into()];
+        ed.line_ending = LineEnding::CrLf;
+        ed.current_line = 3;
+        ed.dirty = true;
+        assert_eq!(
+            "file.txt: line 3 of 3 (100%), modified, crlf",
+            Command::status_line(&ed)
+        );
+
+        let ed = buffer(&[]);
+        assert_eq!(
+            "[no file]: line 0 of 0 (0%), saved, lf",
+            Command::status_line(&ed)
+        );
+    }
+
+    #[test]
+    fn unsaved_changes() {
+        let old: Vec<String> = vec!["a", "b", "c", "d"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let new: Vec<String> = vec!["a", "B!", "c", "new"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let mut out = vec![];
+        Command::write_unsaved(&mut out, &old, &new).unwrap();
+        assert_eq!(
+            "2 lines changed, +3 bytes\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        let mut out = vec![];
+        Command::write_unsaved(&mut out, &old, &new[..1]).unwrap();
+        assert_eq!(
+            "3 lines changed, -6 bytes\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        let mut out = vec![];
+        Command::write_unsaved(&mut out, &old, &old).unwrap();
+        assert_eq!(
+            "0 lines changed, +0 bytes\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn insert_every() {
+        let mut ed = buffer(&["1", "2", "3", "4", "5"]);
+        Command::insert_every(&mut ed, None, None, 2, "--".into()).unwrap();
+        assert_eq!(vec!["1", "2", "--", "3", "4", "--", "5"], ed.data);
+        assert_eq!(7, ed.current_line);
+        assert!(ed.dirty);
+
+        let mut ed = buffer(&["1", "2", "3", "4", "5"]);
+        let start = Some(Address::Numbered(2));
+        let end = Some(Address::Numbered(4));
+        Command::insert_every(&mut ed, start, end, 3, "".into()).unwrap();
+        assert_eq!(vec!["1", "2", "3", "4", "", "5"], ed.data);
+        assert_eq!(5, ed.current_line);
+
+        ed.dirty = false;
+        Command::insert_every(&mut ed, None, None, 10, "".into()).unwrap();
+        assert_eq!(6, ed.lines());
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn address_expressions() {
+        let mut ed = buffer(&["a", "b", "c", "d", "e"]);
+        let expr = |base, n| Address::Expr(Box::new(base), n);
+
+        assert_eq!(
+            3,
+            Command::get_actual_line(&mut ed, expr(Address::LastLine, -2)).unwrap()
+        );
+        assert_eq!(
+            4,
+            Command::get_actual_line(&mut ed, expr(Address::Numbered(1), 3)).unwrap()
+        );
+
+        let err = Command::get_actual_line(&mut ed, expr(Address::LastLine, 1)).unwrap_err();
+        assert_eq!("Invalid address", err.to_string());
+        assert!(Command::get_actual_line(&mut ed, expr(Address::Numbered(2), -2)).is_err());
     }
 }
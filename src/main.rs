@@ -2,9 +2,16 @@
 //!
 //! An `ed` clone, written in Rust.
 
+#[cfg(feature = "clipboard")]
+extern crate arboard;
+extern crate ctrlc;
+extern crate evalexpr;
 extern crate exitfailure;
 extern crate regex;
 extern crate rustyline;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate similar;
 #[macro_use]
 extern crate failure;
 #[macro_use]
@@ -12,10 +19,17 @@ extern crate log;
 extern crate env_logger;
 #[macro_use]
 extern crate structopt;
+extern crate term_size;
+extern crate toml;
 
 use exitfailure::ExitFailure;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use std::fs::{self, File};
+use std::io::{self, BufReader, IsTerminal};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
 mod commands;
@@ -23,7 +37,7 @@ mod parser;
 mod red;
 mod tokenizer;
 
-use commands::{Action, Command};
+use commands::{Action, BlockStyle, CheckFormat, ClipFormat, Command};
 use red::Red;
 
 /// A Rust Editor.
@@ -34,6 +48,74 @@ pub struct Cli {
     /// use STRING as an interactive prompt
     #[structopt(short = "p", long = "prompt", default_value = "")]
     prompt: String,
+    /// don't print byte counts when reading and writing files
+    #[structopt(short = "s", long = "silent")]
+    silent: bool,
+    /// print the number of lines and the path along with the byte count when writing
+    #[structopt(long = "verbose")]
+    verbose: bool,
+    /// refuse to overwrite a file that changed on disk since it was loaded
+    #[structopt(long = "safe-write")]
+    safe_write: bool,
+    /// report lines longer than this many characters with `L`
+    #[structopt(long = "max-col", default_value = "80")]
+    max_col: usize,
+    /// wrap paragraphs reflowed with `jw` to this many characters
+    #[structopt(long = "wrap-width", default_value = "72")]
+    wrap_width: usize,
+    /// number of lines `z` prints at a time (default: the height of the terminal)
+    #[structopt(long = "window")]
+    window: Option<usize>,
+    /// how lines copied with `Y` are formatted: raw, numbered or markdown
+    #[structopt(long = "clip-format", default_value = "raw")]
+    clip_format: ClipFormat,
+    /// format `C` checks the buffer against: json, toml or yaml (default: guessed from the file extension)
+    #[structopt(long = "check-format")]
+    check_format: Option<CheckFormat>,
+    /// width of a tab when counting visual columns
+    #[structopt(long = "tab-width", default_value = "8")]
+    tab_width: usize,
+    /// number shown for the first line by `n`, `N` and `=`
+    #[structopt(long = "line-offset", default_value = "1")]
+    line_offset: usize,
+    /// open the file read-only and keep reading appended lines on EOF, like `tail -f`
+    #[structopt(long = "follow")]
+    follow: bool,
+    /// delimiters `{` wraps lines in: braces or do-end
+    #[structopt(long = "block-style", default_value = "braces")]
+    block_style: BlockStyle,
+    /// print the current line after every command that changes the buffer
+    #[structopt(long = "auto-print")]
+    auto_print: bool,
+    /// print how long each command took to standard error
+    #[structopt(long = "time")]
+    time: bool,
+    /// apply the `s///` expressions in this file, one per line, to the whole buffer on start
+    #[structopt(long = "sed-file")]
+    sed_file: Option<String>,
+    /// read commands from this file instead of the terminal, stopping at the first error
+    #[structopt(long = "script")]
+    script: Option<String>,
+}
+
+/// Print lines appended to the file until interrupted.
+fn follow(ed: &mut Red) {
+    ed.interrupted.store(false, Ordering::SeqCst);
+    while !ed.interrupted.load(Ordering::SeqCst) {
+        match ed.read_appended() {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            Err(err) => {
+                ed.last_error = Some(err.to_string());
+                println!("{}", ed.error_report());
+                return;
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
 }
 
 fn main() -> Result<(), ExitFailure> {
@@ -42,11 +124,56 @@ fn main() -> Result<(), ExitFailure> {
     let args = Cli::from_args();
     let mut rl = Editor::<()>::new();
     let mut ed = Red::new(args.prompt, args.path);
+    ed.safe_write = args.safe_write;
+    ed.max_col = args.max_col;
+    ed.wrap_width = args.wrap_width;
+    ed.window = args.window;
+    ed.clip_format = args.clip_format;
+    ed.check_format = args.check_format;
+    ed.tab_width = args.tab_width;
+    ed.line_offset = args.line_offset;
+    ed.block_style = args.block_style;
+    ed.auto_print = args.auto_print;
+    ed.time = args.time;
+    ed.silent = args.silent;
+    ed.verbose = args.verbose;
+    if args.follow {
+        let path = match ed.path {
+            None => Err(format_err!("--follow needs a file"))?,
+            Some(ref path) => path.clone(),
+        };
+        ed.read_only = true;
+        ed.follow_offset = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    }
+
+    // Ctrl-C aborts the running command instead of killing the editor
+    let interrupted = ed.interrupted.clone();
+    ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst))?;
 
     let size = ed.data_size();
-    if size > 0 {
+    if size > 0 && !ed.silent {
         println!("{}", size);
     }
+    // The file couldn't be read, `h` tells why
+    if ed.last_error.is_some() {
+        println!("?");
+    }
+    if let Some(ref sed_file) = args.sed_file {
+        ed.apply_sed_file(sed_file)?;
+    }
+
+    // Commands come from a script, or from standard input if it isn't a terminal
+    let script = match args.script {
+        Some(ref script) => Some(File::open(script).map(BufReader::new)?),
+        None => None,
+    };
+    if let Some(script) = script {
+        return Ok(ed.run_script(script)?);
+    }
+    if !io::stdin().is_terminal() {
+        let stdin = io::stdin();
+        return Ok(ed.run_script(stdin.lock())?);
+    }
 
     loop {
         debug!("Ed: {:?}", ed);
@@ -58,24 +185,25 @@ fn main() -> Result<(), ExitFailure> {
                     Ok(res) => {
                         debug!("Result: {:?}", res);
 
-                        match res {
-                            Action::Quit => break,
-                            Action::Continue => {}
-                            Action::Unknown => {
-                                println!("?");
-                            }
+                        if res == Action::Quit {
+                            break;
                         }
                     }
                     Err(err) => {
                         debug!("Saving error: {:?}", err);
                         ed.last_error = Some(err.to_string());
-                        println!("?");
+                        println!("{}", ed.error_report());
                     }
                 }
             }
             Err(ReadlineError::Interrupted) => {
                 debug!("Readline Interrupted");
-                println!("?");
+                ed.last_error = Some("Interrupted".into());
+                println!("{}", ed.error_report());
+            }
+            Err(ReadlineError::Eof) if args.follow => {
+                debug!("EOF send, following file.");
+                follow(&mut ed);
             }
             Err(ReadlineError::Eof) => {
                 debug!("EOF send.");
@@ -83,7 +211,7 @@ fn main() -> Result<(), ExitFailure> {
                 match cmd.execute(&mut ed) {
                     Err(err) => {
                         ed.last_error = Some(err.to_string());
-                        println!("?");
+                        println!("{}", ed.error_report());
                     }
                     Ok(Action::Quit) => break,
                     Ok(_) => panic!("Unknown action on EOF"),
@@ -1,7 +1,12 @@
 use failure;
 
 use commands::Address;
+use commands::Case;
+use commands::Column;
 use commands::Command;
+use commands::Escape;
+use commands::LineFormat;
+use commands::Position;
 use tokenizer::Token;
 
 fn parse_address(addr: &str) -> Result<Address, failure::Error> {
@@ -11,17 +16,226 @@ fn parse_address(addr: &str) -> Result<Address, failure::Error> {
         _ => {}
     }
 
-    if &addr[0..1] == "+" || &addr[0..1] == "-" {
-        let n = addr[0..]
-            .parse::<isize>()
-            .map_err(|_| format_err!("Invalid address"))?;
-        return Ok(Address::Offset(n));
+    // A search, optionally followed by offsets from the line found, as in `/re/+2`
+    if addr.starts_with('/') || addr.starts_with('?') {
+        let (pattern, offsets) = parse_search(addr)?;
+        let base = if addr.starts_with('/') {
+            Address::ForwardSearch(pattern)
+        } else {
+            Address::BackwardSearch(pattern)
+        };
+        return Ok(with_offset(base, parse_offset(offsets)?));
     }
 
-    let n = addr
+    // A mark, optionally followed by an offset from it, as in `'a+3`
+    if let Some(mark) = addr.strip_prefix('\'') {
+        let mut chars = mark.chars();
+        let label = match chars.next() {
+            Some(c) if c.is_ascii_lowercase() => c,
+            _ => return Err(format_err!("Invalid address")),
+        };
+        return Ok(Address::Mark(label, parse_offset(chars.as_str())?));
+    }
+
+    // A base address, followed by any number of offsets, as in `$-2` or `.+1+1`
+    let idx = addr.find(['+', '-']).unwrap_or(addr.len());
+    let (base, offset) = (&addr[..idx], parse_offset(&addr[idx..])?);
+    let base = match base {
+        "" | "." => return Ok(Address::Offset(offset)),
+        "$" => Address::LastLine,
+        n => Address::Numbered(
+            n.parse::<usize>()
+                .map_err(|_| format_err!("Invalid address"))?,
+        ),
+    };
+
+    Ok(with_offset(base, offset))
+}
+
+fn with_offset(base: Address, offset: isize) -> Address {
+    if offset == 0 {
+        base
+    } else {
+        Address::Expr(Box::new(base), offset)
+    }
+}
+
+/// Sum up offsets like `+2-1`.
+///
+/// A `+` or `-` without a number counts as 1, as in ed.
+fn parse_offset(offsets: &str) -> Result<isize, failure::Error> {
+    let mut total = 0;
+    let mut rest = offsets;
+    while !rest.is_empty() {
+        let sign = match rest.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(format_err!("Invalid address")),
+        };
+        rest = &rest[1..];
+
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let n = match &rest[..digits] {
+            "" => 1,
+            n => n
+                .parse::<isize>()
+                .map_err(|_| format_err!("Invalid address"))?,
+        };
+        total += sign * n;
+        rest = &rest[digits..];
+    }
+    Ok(total)
+}
+
+/// Parse a search address like `/re/` or `?re?` into its pattern and the rest of the address.
+///
+/// The closing delimiter is optional, an escaped delimiter is part of the pattern.
+/// An empty pattern, as in `//`, stands for the last one used.
+fn parse_search(addr: &str) -> Result<(String, &str), failure::Error> {
+    let mut chars = addr.chars();
+    let delimiter = chars.next().unwrap();
+    let mut pattern = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) if c == delimiter => pattern.push(c),
+                Some(c) => {
+                    pattern.push('\\');
+                    pattern.push(c);
+                }
+                None => pattern.push('\\'),
+            },
+            c if c == delimiter => break,
+            c => pattern.push(c),
+        }
+    }
+
+    Ok((pattern, chars.as_str()))
+}
+
+/// Parse an address that might be followed by a column, as in `12:5` or `12:b5`.
+///
+/// A plain number is a visual column, a `b` prefix makes it a byte column.
+/// Without a line, the column refers to the current line.
+fn parse_position(addr: &str) -> Result<(Address, Option<Column>), failure::Error> {
+    // Search patterns may contain a `:` themselves
+    if addr.starts_with('/') || addr.starts_with('?') {
+        return Ok((parse_address(addr)?, None));
+    }
+
+    let idx = match addr.find(':') {
+        None => return Ok((parse_address(addr)?, None)),
+        Some(idx) => idx,
+    };
+
+    let line = match &addr[..idx] {
+        "" => Address::CurrentLine,
+        line => parse_address(line)?,
+    };
+
+    Ok((line, Some(parse_column(&addr[idx + 1..])?)))
+}
+
+/// Parse a column, a visual one like `5` or a byte column like `b5`.
+fn parse_column(col: &str) -> Result<Column, failure::Error> {
+    let (bytes, col) = match col.strip_prefix('b') {
+        Some(col) => (true, col),
+        None => (false, col),
+    };
+    let n = col
         .parse::<usize>()
-        .map_err(|_| format_err!("Invalid address"))?;
-    Ok(Address::Numbered(n))
+        .map_err(|_| format_err!("Invalid column"))?;
+    if n == 0 {
+        return Err(format_err!("Invalid column"));
+    }
+
+    if bytes {
+        Ok(Column::Byte(n))
+    } else {
+        Ok(Column::Visual(n))
+    }
+}
+
+/// Patterns may contain spaces, which the tokenizer splits into a suffix and an argument.
+/// Glue them back together.
+fn join_suffix(suffix: Option<String>, arg: Option<String>) -> Option<String> {
+    match (suffix, arg) {
+        (Some(mut suffix), Some(arg)) => {
+            suffix.push(' ');
+            suffix.push_str(&arg);
+            Some(suffix)
+        }
+        (suffix, arg) => suffix.or(arg),
+    }
+}
+
+/// Parse a print suffix, as in `2dp`: `p`, `n` or `l` print the current line
+/// afterwards like the commands of the same name.
+fn parse_print_flag(flag: Option<String>) -> Result<Option<LineFormat>, failure::Error> {
+    match flag.as_ref().map(|s| s.trim()) {
+        None | Some("") => Ok(None),
+        Some("p") => Ok(Some(LineFormat::Plain)),
+        Some("n") => Ok(Some(LineFormat::Numbered)),
+        Some("l") => Ok(Some(LineFormat::List)),
+        Some(_) => Err(format_err!("Invalid command suffix")),
+    }
+}
+
+/// Split a print suffix off a target address, as in `m0p`.
+///
+/// A letter right after a `'` names a mark and is kept.
+fn split_print_flag(target: &str) -> (&str, Option<String>) {
+    let mut chars = target.chars().rev();
+    match (chars.next(), chars.next()) {
+        (Some(c), prev) if "pnl".contains(c) && prev != Some('\'') => {
+            (&target[..target.len() - 1], Some(c.to_string()))
+        }
+        _ => (target, None),
+    }
+}
+
+/// Parse the second range of a command working on two ranges, as in `Z5,7`.
+fn parse_target_range(suffix: Option<String>) -> Result<(Address, Address), failure::Error> {
+    let suffix = match suffix {
+        None => return Err(format_err!("Invalid target address")),
+        Some(suffix) => suffix,
+    };
+    match suffix.find(',') {
+        None => {
+            let addr = parse_address(&suffix)?;
+            Ok((addr.clone(), addr))
+        }
+        Some(idx) => Ok((
+            parse_address(&suffix[..idx])?,
+            parse_address(&suffix[idx + 1..])?,
+        )),
+    }
+}
+
+/// The register used when none is named.
+pub const DEFAULT_REGISTER: char = '"';
+
+/// Split a suffix into a leading register name and the rest.
+fn split_register(suffix: Option<String>) -> (char, Option<String>) {
+    let suffix = match suffix {
+        None => return (DEFAULT_REGISTER, None),
+        Some(suffix) => suffix,
+    };
+
+    match suffix.chars().next() {
+        Some(c) if c.is_ascii_lowercase() => {
+            let rest = &suffix[1..];
+            let rest = if rest.is_empty() {
+                None
+            } else {
+                Some(rest.to_string())
+            };
+            (c, rest)
+        }
+        _ => (DEFAULT_REGISTER, Some(suffix)),
+    }
 }
 
 pub fn parse(tokens: &[Token]) -> Result<Command, failure::Error> {
@@ -31,23 +245,40 @@ pub fn parse(tokens: &[Token]) -> Result<Command, failure::Error> {
 
     let mut start = None;
     let mut end = None;
+    let mut column = None;
     let mut cmd = None;
     let mut suffix = None;
     let mut arg = None;
     let mut first_addr = false;
-    let mut separator_found = false;
+    let mut separator = None;
+    let mut whole_buffer = false;
 
     for token in tokens {
         match token {
+            Token::Address("%") if !first_addr => {
+                whole_buffer = true;
+                first_addr = true;
+            }
+            // `^` is the current line and the block indented below it
+            Token::Address("^") if !first_addr => {
+                start = Some(Address::CurrentLine);
+                end = Some(Address::BlockEnd);
+                first_addr = true;
+            }
             Token::Address(addr) if !first_addr => {
-                start = Some(parse_address(addr)?);
+                let (addr, col) = parse_position(addr)?;
+                start = Some(addr);
+                column = col;
                 first_addr = true;
             }
             Token::Address(addr) if first_addr => {
+                if end.is_some() {
+                    return Err(format_err!("Invalid address"));
+                }
                 end = Some(parse_address(addr)?);
             }
-            Token::Separator(_) => {
-                separator_found = true;
+            Token::Separator(sep) => {
+                separator = Some(*sep);
                 first_addr = true;
             }
             Token::Suffix(s) => {
@@ -63,16 +294,33 @@ pub fn parse(tokens: &[Token]) -> Result<Command, failure::Error> {
         }
     }
 
-    // If there was a separator, fix up the range to cover all
-    if separator_found && start.is_none() && end.is_none() {
+    // `%` and a separator without addresses, as in `,p`, cover the whole buffer
+    if whole_buffer && (separator.is_some() || end.is_some()) {
+        return Err(format_err!("Invalid address"));
+    }
+    if whole_buffer || (separator.is_some() && start.is_none() && end.is_none()) {
         start = Some(Address::Numbered(1));
         end = Some(Address::LastLine);
     }
 
+    // With `;`, the second address is relative to the first one
+    if separator == Some(';') {
+        if let (Some(first), Some(second)) = (start.clone(), end.take()) {
+            end = Some(Address::Relative(Box::new(first), Box::new(second)));
+        }
+    }
+
+    // Only a few commands work on a position within a line
+    if column.is_some() && cmd != Some(&'S') && cmd != Some(&'I') {
+        return Err(format_err!("Unexpected column"));
+    }
+
     let cmd = match cmd {
         None if start.is_some() && end.is_none() => {
+            // A trailing `;` sets the current line without printing it
             return Ok(Command::Jump {
                 address: start.unwrap(),
+                silent: separator == Some(';'),
             });
         }
         None => return Ok(Command::Noop),
@@ -80,60 +328,484 @@ pub fn parse(tokens: &[Token]) -> Result<Command, failure::Error> {
     };
 
     let cmd = match cmd {
-        'p' => Command::Print { start, end },
-        'n' => Command::Numbered { start, end },
-        'd' => Command::Delete { start, end },
-        'w' => Command::Write {
+        'n' if suffix.as_ref().map(|s| s.starts_with('+')).unwrap_or(false) => {
+            // `n+[width] [separator]` adds the numbers to the lines themselves
+            let width = &suffix.as_ref().unwrap()[1..];
+            let width = if width.is_empty() {
+                None
+            } else {
+                Some(
+                    width
+                        .parse::<usize>()
+                        .map_err(|_| format_err!("Invalid width"))?,
+                )
+            };
+
+            Command::NumberLines {
+                start,
+                end,
+                width,
+                separator: arg.unwrap_or_else(|| "\t".into()),
+            }
+        }
+        'p' | 'n' | 'l' => {
+            // A print suffix switches to another format, as in `pl`
+            let own = match *cmd {
+                'p' => LineFormat::Plain,
+                'n' => LineFormat::Numbered,
+                _ => LineFormat::List,
+            };
+            let format = match (own, parse_print_flag(suffix)?) {
+                (format, None) | (format, Some(LineFormat::Plain)) => format,
+                (LineFormat::Plain, Some(format)) => format,
+                (own, Some(format)) if own == format => own,
+                _ => return Err(format_err!("Invalid command suffix")),
+            };
+
+            match format {
+                LineFormat::Numbered => Command::Numbered { start, end },
+                LineFormat::List => Command::List { start, end },
+                _ => Command::Print { start, end },
+            }
+        }
+        'N' => Command::Snippet { start, end },
+        'z' => {
+            if end.is_some() {
+                return Err(format_err!("Unexpected address"));
+            }
+            let count = match suffix.or(arg) {
+                None => None,
+                Some(count) => match count.parse::<usize>() {
+                    Ok(count) if count > 0 => Some(count),
+                    _ => return Err(format_err!("Invalid window size")),
+                },
+            };
+
+            Command::Scroll { start, count }
+        }
+        'A' => Command::Changes { start, end },
+        'O' => Command::Outline {
+            start,
+            end,
+            pattern: join_suffix(suffix, arg),
+        },
+        'Y' => Command::Clip { start, end },
+        '{' => Command::Block { start, end },
+        'J' => Command::FormatJson { start, end },
+        'C' => Command::Check,
+        'd' => Command::Delete {
+            start,
+            end,
+            print: parse_print_flag(join_suffix(suffix, arg))?,
+        },
+        'w' if suffix.as_ref().map(|s| s == "q").unwrap_or(false) => Command::WriteQuit {
             start,
             end,
             file: arg,
         },
+        'w' | 'W' => {
+            // `w >>file` appends like `W file`
+            let (file, append) = match join_suffix(suffix.clone(), arg.clone()) {
+                Some(ref target) if target.starts_with(">>") => {
+                    let file = target[2..].trim();
+                    let file = if file.is_empty() {
+                        None
+                    } else {
+                        Some(file.to_string())
+                    };
+                    (file, true)
+                }
+                _ => (arg, *cmd == 'W'),
+            };
+
+            Command::Write {
+                start,
+                end,
+                file,
+                force: suffix.as_ref().map(|s| s == "!").unwrap_or(false),
+                append,
+            }
+        }
         'i' => Command::Insert {
             before: start.or(end),
+            print: parse_print_flag(join_suffix(suffix, arg))?,
         },
         'a' => Command::Append {
             after: end.or(start),
+            print: parse_print_flag(join_suffix(suffix, arg))?,
         },
+        '#' => Command::Comment {
+            address: end.or(start),
+        },
+        '=' if suffix.as_ref().map(|s| s == "v").unwrap_or(false) => Command::Status,
+        '=' if suffix.as_ref().map(|s| s == "e").unwrap_or(false) => Command::Evaluate {
+            address: end.or(start),
+        },
+        '=' => Command::LineNumber {
+            address: end.or(start),
+        },
+        'k' => {
+            let label = match suffix.as_deref() {
+                Some(s) if s.len() == 1 && s.chars().all(|c| c.is_ascii_lowercase()) => {
+                    s.chars().next().unwrap()
+                }
+                _ => return Err(format_err!("Invalid mark character")),
+            };
+
+            Command::Mark {
+                address: end.or(start),
+                label,
+            }
+        }
+        'g' | 'v' | 'G' | 'V' => {
+            let suffix = join_suffix(suffix, arg).unwrap_or_default();
+            let inverted = *cmd == 'v' || *cmd == 'V';
+            let (invert, rest) = match suffix.strip_prefix('!') {
+                Some(rest) => (!inverted, rest),
+                None => (inverted, &suffix[..]),
+            };
+            let rest = match rest.strip_prefix('/') {
+                Some(rest) => rest,
+                None => return Err(format_err!("Missing pattern delimiter")),
+            };
+            let (regex, command) = match rest.find('/') {
+                None => (rest, ""),
+                Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+            };
+
+            // The interactive form asks for the command on every line
+            if *cmd == 'G' || *cmd == 'V' {
+                if !command.is_empty() {
+                    return Err(format_err!("Unexpected command"));
+                }
+                Command::InteractiveGlobal {
+                    start,
+                    end,
+                    regex: regex.to_string(),
+                    invert,
+                }
+            } else {
+                Command::Global {
+                    start,
+                    end,
+                    regex: regex.to_string(),
+                    invert,
+                    command: command.to_string(),
+                }
+            }
+        }
+        '!' => {
+            let command = match arg {
+                None => return Err(format_err!("Missing shell command")),
+                Some(command) => command,
+            };
+            // With an address, the lines are filtered through the command
+            if start.is_some() || end.is_some() {
+                Command::Filter {
+                    start,
+                    end,
+                    command,
+                }
+            } else {
+                Command::Shell { command }
+            }
+        }
+        '|' => Command::SoftWrap,
+        'P' => Command::TogglePrompt,
         'h' => Command::Help,
-        'q' => Command::Quit { force: false },
-        'Q' => Command::Quit { force: true },
-        'e' => Command::Edit { file: arg },
-        'c' => Command::Change { start, end },
+        'H' => Command::ToggleHelp,
+        'u' => Command::Undo,
+        'q' if suffix.as_ref().map(|s| s == "?").unwrap_or(false) => Command::Unsaved,
+        'q' | 'Q' => {
+            if start.is_some() || end.is_some() {
+                return Err(format_err!("Unexpected address"));
+            }
+            Command::Quit { force: *cmd == 'Q' }
+        }
+        'e' => Command::Edit {
+            file: arg,
+            force: suffix.as_ref().map(|s| s == "!").unwrap_or(false),
+        },
+        'f' => {
+            if start.is_some() || end.is_some() {
+                return Err(format_err!("Unexpected address"));
+            }
+            if suffix.is_some() {
+                return Err(format_err!("Invalid command suffix"));
+            }
+            Command::Filename { name: arg }
+        }
+        'D' => Command::Diff { file: arg },
+        'U' => Command::Patch { file: arg },
+        'R' => Command::Record { file: arg },
+        'c' => Command::Change {
+            start,
+            end,
+            print: parse_print_flag(join_suffix(suffix, arg))?,
+        },
         'r' => Command::Read {
             after: end.or(start),
             file: arg,
         },
-        'm' => {
-            let suffix = match suffix {
+        'j' => match suffix.as_ref().map(|s| &s[..]) {
+            Some("w") => Command::Reflow { start, end },
+            _ => Command::Join {
+                start,
+                end,
+                print: parse_print_flag(join_suffix(suffix, arg))?,
+            },
+        },
+        'm' | 't' => {
+            let (suffix, arg) = match suffix {
                 None => return Err(format_err!("Invalid target address")),
-                Some(suffix) => suffix,
+                // A search pattern may contain spaces, as in `m/end of/`
+                Some(ref suffix) if suffix.starts_with('/') || suffix.starts_with('?') => {
+                    (join_suffix(Some(suffix.clone()), arg).unwrap(), None)
+                }
+                Some(suffix) => (suffix, arg),
+            };
+            let (dest, flag) = split_print_flag(&suffix);
+            let dest = parse_address(dest.trim_end())?;
+            let print = parse_print_flag(flag.or(arg))?;
+
+            if *cmd == 'm' {
+                Command::Move {
+                    start,
+                    end,
+                    dest,
+                    print,
+                }
+            } else {
+                Command::Transfer {
+                    start,
+                    end,
+                    dest,
+                    print,
+                }
+            }
+        }
+        's' => Command::Substitute {
+            start,
+            end,
+            arg: join_suffix(suffix, arg),
+        },
+        '@' => {
+            let count = match suffix {
+                None => 1,
+                Some(suffix) => suffix
+                    .parse::<isize>()
+                    .map_err(|_| format_err!("Invalid rotation count"))?,
+            };
+
+            Command::Rotate { start, end, count }
+        }
+        'L' => {
+            let width = match suffix.or(arg) {
+                None => None,
+                Some(width) => Some(
+                    width
+                        .parse::<usize>()
+                        .map_err(|_| format_err!("Invalid line length"))?,
+                ),
             };
-            let dest = parse_address(&suffix)?;
 
-            Command::Move { start, end, dest }
+            Command::LongLines { start, end, width }
+        }
+        '*' => {
+            let interval = match suffix {
+                None => return Err(format_err!("Missing interval")),
+                Some(n) => n
+                    .parse::<usize>()
+                    .map_err(|_| format_err!("Invalid interval"))?,
+            };
+            if interval == 0 {
+                return Err(format_err!("Invalid interval"));
+            }
+
+            Command::InsertEvery {
+                start,
+                end,
+                interval,
+                text: arg.unwrap_or_default(),
+            }
+        }
+        'F' => Command::Align {
+            start,
+            end,
+            delimiter: join_suffix(suffix, arg),
+        },
+        'E' => {
+            let text = join_suffix(suffix, arg).unwrap_or_default();
+            let (reverse, scheme) = match text.strip_prefix('!') {
+                Some(scheme) => (true, scheme.trim()),
+                None => (false, text.trim()),
+            };
+            if scheme.is_empty() {
+                return Err(format_err!("Missing escape scheme"));
+            }
+
+            Command::Escape {
+                start,
+                end,
+                scheme: scheme.parse::<Escape>()?,
+                reverse,
+            }
         }
-        's' => {
-            let mut suffix = match suffix {
+        'K' => {
+            let span = match suffix {
+                None => return Err(format_err!("Missing columns")),
+                Some(span) => span,
+            };
+            let (from, to) = match span.find('-') {
                 None => {
-                    return Ok(Command::Substitute {
-                        start,
-                        end,
-                        arg: None,
-                    })
+                    let col = parse_column(&span)?;
+                    (col, col)
                 }
-                Some(suffix) => suffix,
+                Some(idx) => (parse_column(&span[..idx])?, parse_column(&span[idx + 1..])?),
             };
-            if let Some(arg) = arg {
-                suffix.push_str(" ");
-                suffix.push_str(&arg);
+            match (from, to) {
+                (Column::Visual(a), Column::Visual(b)) | (Column::Byte(a), Column::Byte(b))
+                    if a > b =>
+                {
+                    return Err(format_err!("Invalid column"))
+                }
+                _ => {}
             }
 
-            Command::Substitute {
+            Command::Cut {
+                start,
+                end,
+                from,
+                to,
+            }
+        }
+        'M' => Command::RepeatInput {
+            after: end.or(start),
+        },
+        '_' => {
+            let fill = match suffix {
+                None => '-',
+                Some(ref s) if s.chars().count() == 1 => s.chars().next().unwrap(),
+                Some(_) => return Err(format_err!("Invalid ruler character")),
+            };
+            let width = match arg {
+                None => None,
+                Some(width) => Some(
+                    width
+                        .parse::<usize>()
+                        .map_err(|_| format_err!("Invalid ruler width"))?,
+                ),
+            };
+
+            Command::Ruler {
+                after: end.or(start),
+                fill,
+                width,
+            }
+        }
+        '~' => {
+            let suffix = suffix.unwrap_or_default();
+            let case = match suffix.chars().next() {
+                Some('u') => Case::Upper,
+                Some('l') => Case::Lower,
+                Some('t') => Case::Title,
+                _ => return Err(format_err!("Unknown case")),
+            };
+            let rest = if suffix.len() > 1 {
+                Some(suffix[1..].to_string())
+            } else {
+                None
+            };
+
+            Command::ChangeCase {
+                start,
+                end,
+                case,
+                pattern: join_suffix(rest, arg),
+            }
+        }
+        'T' => Command::Translate {
+            start,
+            end,
+            arg: join_suffix(suffix, arg),
+        },
+        'y' => {
+            let (register, rest) = split_register(suffix);
+            if rest.is_some() {
+                return Err(format_err!("Invalid register"));
+            }
+
+            Command::Yank {
+                start,
+                end,
+                register,
+            }
+        }
+        'x' => {
+            let (register, pattern) = split_register(join_suffix(suffix, arg));
+
+            Command::Put {
+                after: end.or(start),
+                register,
+                pattern,
+            }
+        }
+        'S' => {
+            let column = match column {
+                None => return Err(format_err!("Missing column")),
+                Some(column) => column,
+            };
+
+            Command::Split {
+                position: Position {
+                    line: start.unwrap_or(Address::CurrentLine),
+                    column,
+                },
+            }
+        }
+        'I' => Command::InsertAt {
+            position: Position {
+                line: start.unwrap_or(Address::CurrentLine),
+                column: column.unwrap_or(Column::Visual(1)),
+            },
+            text: join_suffix(suffix, arg).unwrap_or_default(),
+        },
+        'B' => {
+            let squeeze = match suffix.as_ref().map(|s| &s[..]) {
+                None => false,
+                Some("s") => true,
+                Some(_) => return Err(format_err!("Invalid command suffix")),
+            };
+
+            Command::Compress {
+                start,
+                end,
+                squeeze,
+            }
+        }
+        'Z' => {
+            let (other_start, other_end) = parse_target_range(suffix)?;
+            Command::Interleave {
+                start,
+                end,
+                other_start,
+                other_end,
+            }
+        }
+        'X' => {
+            let (other_start, other_end) = parse_target_range(suffix)?;
+            Command::Transpose {
                 start,
                 end,
-                arg: Some(suffix),
+                other_start,
+                other_end,
             }
         }
+        'o' => Command::Extract {
+            start,
+            end,
+            arg: join_suffix(suffix, arg),
+        },
         _ => Command::Noop,
     };
     Ok(cmd)
@@ -151,6 +823,10 @@ mod test {
         assert_eq!(Address::Numbered(23), parse_address("23").unwrap());
         assert_eq!(Address::Offset(2), parse_address("+2").unwrap());
         assert_eq!(Address::Offset(-3), parse_address("-3").unwrap());
+        assert_eq!(Address::Offset(1), parse_address("+").unwrap());
+        assert_eq!(Address::Offset(-1), parse_address("-").unwrap());
+        assert_eq!(Address::Offset(2), parse_address("++").unwrap());
+        assert_eq!(Address::Offset(-3), parse_address("---").unwrap());
     }
 
     #[test]
@@ -176,65 +852,192 @@ mod test {
     }
 
     #[test]
-    fn parse_write() {
+    fn parse_number_lines() {
         assert_eq!(
-            Command::Write {
+            Command::NumberLines {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine),
+                width: None,
+                separator: "\t".into(),
+            },
+            parse(&tokenize(",n+").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::NumberLines {
                 start: None,
                 end: None,
-                file: Some("file.txt".into())
+                width: Some(3),
+                separator: ".".into(),
             },
-            parse(&tokenize("w file.txt").unwrap()).unwrap()
+            parse(&tokenize("n+3 .").unwrap()).unwrap()
         );
+        assert!(parse(&tokenize("n+x").unwrap()).is_err());
     }
 
     #[test]
-    fn parse_append() {
+    fn parse_list() {
         assert_eq!(
-            Command::Append {
-                after: Some(Address::Numbered(2)),
+            Command::List {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine)
             },
-            parse(&tokenize("1,2a").unwrap()).unwrap()
-        );
-
-        assert_eq!(
-            Command::Append { after: None },
-            parse(&tokenize("a").unwrap()).unwrap()
+            parse(&tokenize(",l").unwrap()).unwrap()
         );
+    }
 
+    #[test]
+    fn parse_write() {
         assert_eq!(
-            Command::Append {
-                after: Some(Address::Numbered(1)),
+            Command::Write {
+                start: None,
+                end: None,
+                file: Some("file.txt".into()),
+                force: false,
+                append: false,
             },
-            parse(&tokenize("1a").unwrap()).unwrap()
+            parse(&tokenize("w file.txt").unwrap()).unwrap()
         );
-    }
 
-    #[test]
-    fn parse_jumps() {
         assert_eq!(
-            Command::Jump {
-                address: Address::Numbered(2)
+            Command::Write {
+                start: None,
+                end: None,
+                file: Some("file.txt".into()),
+                force: true,
+                append: false,
             },
-            parse(&tokenize("2").unwrap()).unwrap()
+            parse(&tokenize("w! file.txt").unwrap()).unwrap()
         );
 
         assert_eq!(
-            Command::Jump {
-                address: Address::CurrentLine
+            Command::Write {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(2)),
+                file: Some("log.txt".into()),
+                force: false,
+                append: true,
             },
-            parse(&tokenize(".").unwrap()).unwrap()
+            parse(&tokenize("1,2W log.txt").unwrap()).unwrap()
         );
+
+        let append = Command::Write {
+            start: None,
+            end: None,
+            file: Some("log.txt".into()),
+            force: false,
+            append: true,
+        };
+        assert_eq!(append, parse(&tokenize("w >>log.txt").unwrap()).unwrap());
+        assert_eq!(append, parse(&tokenize("w>> log.txt").unwrap()).unwrap());
     }
 
     #[test]
-    fn parse_substitute() {
+    fn parse_write_quit() {
         assert_eq!(
-            Command::Substitute {
+            Command::WriteQuit {
                 start: None,
                 end: None,
-                arg: Some("/RE/replacement/flags".into())
+                file: None,
             },
-            parse(&tokenize("s/RE/replacement/flags").unwrap()).unwrap()
+            parse(&tokenize("wq").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::WriteQuit {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine),
+                file: Some("out.txt".into()),
+            },
+            parse(&tokenize("1,$wq out.txt").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_filename() {
+        assert_eq!(
+            Command::Filename { name: None },
+            parse(&tokenize("f").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Filename {
+                name: Some("new.txt".into())
+            },
+            parse(&tokenize("f new.txt").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("1f").unwrap()).is_err());
+        assert!(parse(&tokenize("fnew.txt").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_append() {
+        assert_eq!(
+            Command::Append {
+                after: Some(Address::Numbered(2)),
+                print: None,
+            },
+            parse(&tokenize("1,2a").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Append {
+                after: None,
+                print: None
+            },
+            parse(&tokenize("a").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Append {
+                after: Some(Address::Numbered(1)),
+                print: None,
+            },
+            parse(&tokenize("1a").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_jumps() {
+        assert_eq!(
+            Command::Jump {
+                address: Address::Numbered(2),
+                silent: false,
+            },
+            parse(&tokenize("2").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Jump {
+                address: Address::CurrentLine,
+                silent: false,
+            },
+            parse(&tokenize(".").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Jump {
+                address: Address::Numbered(12),
+                silent: true,
+            },
+            parse(&tokenize("12;").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Jump {
+                address: Address::Offset(1),
+                silent: false,
+            },
+            parse(&tokenize("+").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_substitute() {
+        assert_eq!(
+            Command::Substitute {
+                start: None,
+                end: None,
+                arg: Some("/RE/replacement/flags".into())
+            },
+            parse(&tokenize("s/RE/replacement/flags").unwrap()).unwrap()
         );
 
         assert_eq!(
@@ -245,5 +1048,932 @@ mod test {
             },
             parse(&tokenize("1,10s/RE/replacement/flags").unwrap()).unwrap()
         );
+
+        assert_eq!(
+            Command::Substitute {
+                start: None,
+                end: None,
+                arg: Some("/dog/cat/".into())
+            },
+            parse(&tokenize("s/dog/cat/").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Substitute {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(5)),
+                arg: Some("/a/b/g".into())
+            },
+            parse(&tokenize("1,5s/a/b/g").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Substitute {
+                start: Some(Address::Numbered(3)),
+                end: None,
+                arg: Some("/x/y/".into())
+            },
+            parse(&tokenize("3s/x/y/").unwrap()).unwrap()
+        );
+
+        // Spaces split the tokens, but not the pattern
+        assert_eq!(
+            Command::Substitute {
+                start: None,
+                end: None,
+                arg: Some("/a dog/the cat/".into())
+            },
+            parse(&tokenize("s/a dog/the cat/").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_comment() {
+        assert_eq!(
+            Command::Comment {
+                address: Some(Address::Numbered(12))
+            },
+            parse(&tokenize("12#note").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Comment { address: None },
+            parse(&tokenize("# just a note").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_extract() {
+        assert_eq!(
+            Command::Extract {
+                start: None,
+                end: None,
+                arg: Some(r"/(\d+) items/".into())
+            },
+            parse(&tokenize(r"o/(\d+) items/").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_rotate() {
+        assert_eq!(
+            Command::Rotate {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(5)),
+                count: 2,
+            },
+            parse(&tokenize("1,5@2").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Rotate {
+                start: None,
+                end: None,
+                count: -1,
+            },
+            parse(&tokenize("@-1").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_long_lines() {
+        assert_eq!(
+            Command::LongLines {
+                start: None,
+                end: None,
+                width: None,
+            },
+            parse(&tokenize("L").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::LongLines {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(20)),
+                width: Some(72),
+            },
+            parse(&tokenize("1,20L 72").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_registers() {
+        assert_eq!(
+            Command::Yank {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(2)),
+                register: 'a',
+            },
+            parse(&tokenize("1,2ya").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Put {
+                after: None,
+                register: DEFAULT_REGISTER,
+                pattern: None,
+            },
+            parse(&tokenize("x").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Put {
+                after: Some(Address::Numbered(3)),
+                register: 'b',
+                pattern: Some("/some thing/".into()),
+            },
+            parse(&tokenize("3xb/some thing/").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("y1").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_positions() {
+        assert_eq!(
+            Command::Split {
+                position: Position {
+                    line: Address::Numbered(12),
+                    column: Column::Visual(5),
+                },
+            },
+            parse(&tokenize("12:5S").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::InsertAt {
+                position: Position {
+                    line: Address::CurrentLine,
+                    column: Column::Byte(3),
+                },
+                text: "some text".into(),
+            },
+            parse(&tokenize(":b3I some text").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("12S").unwrap()).is_err());
+        assert!(parse(&tokenize("12:0S").unwrap()).is_err());
+        assert!(parse(&tokenize("12:5p").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_line_number() {
+        assert_eq!(
+            Command::LineNumber { address: None },
+            parse(&tokenize("=").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::LineNumber {
+                address: Some(Address::CurrentLine)
+            },
+            parse(&tokenize(".=").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_evaluate() {
+        assert_eq!(
+            Command::Evaluate { address: None },
+            parse(&tokenize("=e").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Evaluate {
+                address: Some(Address::Numbered(3))
+            },
+            parse(&tokenize("3=e").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_mark() {
+        assert_eq!(
+            Command::Mark {
+                address: Some(Address::Numbered(4)),
+                label: 'a'
+            },
+            parse(&tokenize("4ka").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("k").unwrap()).is_err());
+        assert!(parse(&tokenize("kA").unwrap()).is_err());
+        assert!(parse(&tokenize("kab").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_mark_address() {
+        assert_eq!(
+            Command::Print {
+                start: Some(Address::Mark('a', 3)),
+                end: Some(Address::Mark('a', 10))
+            },
+            parse(&tokenize("'a+3,'a+10p").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Delete {
+                start: Some(Address::Mark('x', 0)),
+                end: Some(Address::Mark('y', -1)),
+                print: None,
+            },
+            parse(&tokenize("'x,'y-d").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("'A").unwrap()).is_err());
+        assert!(parse(&tokenize("'a3p").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_transpose() {
+        assert_eq!(
+            Command::Transpose {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(3)),
+                other_start: Address::Numbered(10),
+                other_end: Address::Numbered(12),
+            },
+            parse(&tokenize("1,3X10,12").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("1,3X").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_change_case() {
+        assert_eq!(
+            Command::ChangeCase {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine),
+                case: Case::Title,
+                pattern: None,
+            },
+            parse(&tokenize(",~t").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::ChangeCase {
+                start: None,
+                end: None,
+                case: Case::Upper,
+                pattern: Some("/a b/".into()),
+            },
+            parse(&tokenize("~u/a b/").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("~").unwrap()).is_err());
+        assert!(parse(&tokenize("~x").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_ruler() {
+        assert_eq!(
+            Command::Ruler {
+                after: None,
+                fill: '-',
+                width: None,
+            },
+            parse(&tokenize("_").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Ruler {
+                after: Some(Address::Numbered(3)),
+                fill: '=',
+                width: Some(40),
+            },
+            parse(&tokenize("3_= 40").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Ruler {
+                after: None,
+                fill: '-',
+                width: Some(20),
+            },
+            parse(&tokenize("_ 20").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("_ab").unwrap()).is_err());
+        assert!(parse(&tokenize("_- wide").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_cut() {
+        assert_eq!(
+            Command::Cut {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine),
+                from: Column::Visual(5),
+                to: Column::Visual(10),
+            },
+            parse(&tokenize("1,$K5-10").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Cut {
+                start: None,
+                end: None,
+                from: Column::Byte(3),
+                to: Column::Byte(3),
+            },
+            parse(&tokenize("Kb3").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("K").unwrap()).is_err());
+        assert!(parse(&tokenize("K0-3").unwrap()).is_err());
+        assert!(parse(&tokenize("K5-2").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_global() {
+        assert_eq!(
+            Command::Global {
+                start: None,
+                end: None,
+                regex: "foo".into(),
+                invert: false,
+                command: "d".into(),
+            },
+            parse(&tokenize("g/foo/d").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Global {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(5)),
+                regex: "a b".into(),
+                invert: true,
+                command: "s/x/y/".into(),
+            },
+            parse(&tokenize("1,5v/a b/s/x/y/").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Global {
+                start: None,
+                end: None,
+                regex: "foo".into(),
+                invert: true,
+                command: "".into(),
+            },
+            parse(&tokenize("g!/foo").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::InteractiveGlobal {
+                start: None,
+                end: None,
+                regex: "foo".into(),
+                invert: true,
+            },
+            parse(&tokenize("V/foo/").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::InteractiveGlobal {
+                start: None,
+                end: None,
+                regex: "foo".into(),
+                invert: true,
+            },
+            parse(&tokenize("G!/foo/").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::InteractiveGlobal {
+                start: None,
+                end: None,
+                regex: "foo".into(),
+                invert: false,
+            },
+            parse(&tokenize("V!/foo/").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("g").unwrap()).is_err());
+        assert!(parse(&tokenize("gfoo").unwrap()).is_err());
+        assert!(parse(&tokenize("G/foo/p").unwrap()).is_err());
+        assert!(parse(&tokenize("V/foo/p").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_escape() {
+        assert_eq!(
+            Command::Escape {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine),
+                scheme: Escape::Json,
+                reverse: false,
+            },
+            parse(&tokenize("1,$E json").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Escape {
+                start: None,
+                end: None,
+                scheme: Escape::Shell,
+                reverse: true,
+            },
+            parse(&tokenize("E! shell").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("E").unwrap()).is_err());
+        assert!(parse(&tokenize("E! rot13").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_search_address() {
+        assert_eq!(
+            Command::Delete {
+                start: Some(Address::ForwardSearch("TODO".into())),
+                end: None,
+                print: None,
+            },
+            parse(&tokenize("/TODO/d").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Print {
+                start: Some(Address::BackwardSearch("a?b".into())),
+                end: Some(Address::ForwardSearch("c/d: \\d".into())),
+            },
+            parse(&tokenize("?a\\?b?,/c\\/d: \\d/p").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Jump {
+                address: Address::ForwardSearch("main".into()),
+                silent: false,
+            },
+            parse(&tokenize("/main").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Transfer {
+                start: None,
+                end: None,
+                dest: Address::BackwardSearch("end of".into()),
+                print: Some(LineFormat::Plain),
+            },
+            parse(&tokenize("t?end of? p").unwrap()).unwrap()
+        );
+
+        // An empty pattern repeats the last search
+        assert_eq!(
+            Command::Print {
+                start: Some(Address::ForwardSearch("".into())),
+                end: None,
+            },
+            parse(&tokenize("//p").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("/a/b,3p").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_block_range() {
+        assert_eq!(
+            Command::Delete {
+                start: Some(Address::CurrentLine),
+                end: Some(Address::BlockEnd),
+                print: None,
+            },
+            parse(&tokenize("^d").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("^,5p").unwrap()).is_err());
+        assert!(parse(&tokenize("1,^p").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_relative_range() {
+        assert_eq!(
+            Command::Print {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Offset(2)),
+            },
+            parse(&tokenize("1,+2p").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Print {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Relative(
+                    Box::new(Address::Numbered(1)),
+                    Box::new(Address::Offset(2))
+                )),
+            },
+            parse(&tokenize("1;+2p").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_join() {
+        assert_eq!(
+            Command::Join {
+                start: None,
+                end: None,
+                print: None,
+            },
+            parse(&tokenize("j").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Join {
+                start: Some(Address::Numbered(2)),
+                end: Some(Address::Numbered(4)),
+                print: None,
+            },
+            parse(&tokenize("2,4j").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Reflow {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(10))
+            },
+            parse(&tokenize("1,10jw").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("jx").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_edit() {
+        assert_eq!(
+            Command::Edit {
+                file: Some("file.txt".into()),
+                force: false,
+            },
+            parse(&tokenize("e file.txt").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Edit {
+                file: None,
+                force: true,
+            },
+            parse(&tokenize("e!").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_whole_buffer() {
+        let whole = (Some(Address::Numbered(1)), Some(Address::LastLine));
+        assert_eq!(
+            Command::Print {
+                start: whole.0.clone(),
+                end: whole.1.clone(),
+            },
+            parse(&tokenize("%p").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Delete {
+                start: whole.0.clone(),
+                end: whole.1.clone(),
+                print: None,
+            },
+            parse(&tokenize("%d").unwrap()).unwrap()
+        );
+        assert_eq!(
+            parse(&tokenize(",s/a/b/g").unwrap()).unwrap(),
+            parse(&tokenize("%s/a/b/g").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("%,5p").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_scroll() {
+        assert_eq!(
+            Command::Scroll {
+                start: None,
+                count: None,
+            },
+            parse(&tokenize("z").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Scroll {
+                start: Some(Address::Numbered(5)),
+                count: Some(10),
+            },
+            parse(&tokenize("5z10").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Scroll {
+                start: None,
+                count: Some(10),
+            },
+            parse(&tokenize("z 10").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("z0").unwrap()).is_err());
+        assert!(parse(&tokenize("1,5z").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_print_formats() {
+        let range = (Some(Address::Numbered(1)), Some(Address::LastLine));
+        assert_eq!(
+            Command::List {
+                start: range.0.clone(),
+                end: range.1.clone(),
+            },
+            parse(&tokenize("1,$l").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::List {
+                start: range.0.clone(),
+                end: range.1.clone(),
+            },
+            parse(&tokenize("1,$pl").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Numbered {
+                start: range.0.clone(),
+                end: range.1.clone(),
+            },
+            parse(&tokenize("1,$pn").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::List {
+                start: None,
+                end: None,
+            },
+            parse(&tokenize("lp").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("nl").unwrap()).is_err());
+        assert!(parse(&tokenize("px").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_print_suffix() {
+        let delete = |print| Command::Delete {
+            start: Some(Address::Numbered(2)),
+            end: None,
+            print,
+        };
+        assert_eq!(delete(None), parse(&tokenize("2d").unwrap()).unwrap());
+        assert_eq!(
+            delete(Some(LineFormat::Plain)),
+            parse(&tokenize("2dp").unwrap()).unwrap()
+        );
+        assert_eq!(
+            delete(Some(LineFormat::Plain)),
+            parse(&tokenize("2d p").unwrap()).unwrap()
+        );
+        assert_eq!(
+            delete(Some(LineFormat::List)),
+            parse(&tokenize("2dl").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("2dx").unwrap()).is_err());
+
+        assert_eq!(
+            Command::Move {
+                start: Some(Address::Numbered(5)),
+                end: None,
+                dest: Address::Numbered(0),
+                print: Some(LineFormat::Numbered),
+            },
+            parse(&tokenize("5m0n").unwrap()).unwrap()
+        );
+        // A mark named like a suffix is still the target
+        assert_eq!(
+            Command::Move {
+                start: Some(Address::Numbered(5)),
+                end: None,
+                dest: Address::Mark('p', 0),
+                print: None,
+            },
+            parse(&tokenize("5m'p").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Append {
+                after: None,
+                print: Some(LineFormat::Plain),
+            },
+            parse(&tokenize("ap").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_move() {
+        assert_eq!(
+            Command::Move {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(2)),
+                dest: Address::Numbered(3),
+                print: None,
+            },
+            parse(&tokenize("1,2m3").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Move {
+                start: None,
+                end: None,
+                dest: Address::Expr(Box::new(Address::LastLine), -1),
+                print: None,
+            },
+            parse(&tokenize("m$-1").unwrap()).unwrap()
+        );
+        let err = parse(&tokenize("1,2m").unwrap()).unwrap_err();
+        assert_eq!("Invalid target address", err.to_string());
+    }
+
+    #[test]
+    fn parse_read() {
+        assert_eq!(
+            Command::Read {
+                after: None,
+                file: Some("file.txt".into()),
+            },
+            parse(&tokenize("r file.txt").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Read {
+                after: Some(Address::LastLine),
+                file: None,
+            },
+            parse(&tokenize("$r").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_transfer() {
+        assert_eq!(
+            Command::Transfer {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(2)),
+                dest: Address::Numbered(4),
+                print: None,
+            },
+            parse(&tokenize("1,2t4").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::Transfer {
+                start: None,
+                end: None,
+                dest: Address::LastLine,
+                print: None,
+            },
+            parse(&tokenize("t$").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("1t").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_shell() {
+        assert_eq!(
+            Command::Shell {
+                command: "make -j 4".into()
+            },
+            parse(&tokenize("!make -j 4").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Shell {
+                command: "!".into()
+            },
+            parse(&tokenize("!!").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("!").unwrap()).is_err());
+        assert_eq!(
+            Command::Filter {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(5)),
+                command: "sort -r".into(),
+            },
+            parse(&tokenize("1,5!sort -r").unwrap()).unwrap()
+        );
+        assert_eq!(
+            Command::Filter {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine),
+                command: "fmt -w 72".into(),
+            },
+            parse(&tokenize(",!fmt -w 72").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("1,5!").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_status() {
+        assert_eq!(Command::Status, parse(&tokenize("=v").unwrap()).unwrap());
+        assert_eq!(Command::SoftWrap, parse(&tokenize("|").unwrap()).unwrap());
+        assert_eq!(
+            Command::TogglePrompt,
+            parse(&tokenize("P").unwrap()).unwrap()
+        );
+        assert_eq!(Command::ToggleHelp, parse(&tokenize("H").unwrap()).unwrap());
+        assert_eq!(
+            Command::LineNumber {
+                address: Some(Address::Numbered(3))
+            },
+            parse(&tokenize("3=").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_unsaved() {
+        assert_eq!(Command::Unsaved, parse(&tokenize("q?").unwrap()).unwrap());
+        assert_eq!(
+            Command::Quit { force: false },
+            parse(&tokenize("q").unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_force_quit() {
+        assert_eq!(
+            Command::Quit { force: true },
+            parse(&tokenize("Q").unwrap()).unwrap()
+        );
+        assert!(parse(&tokenize("1Q").unwrap()).is_err());
+        assert!(parse(&tokenize("1,$q").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_insert_every() {
+        assert_eq!(
+            Command::InsertEvery {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine),
+                interval: 5,
+                text: "---".into(),
+            },
+            parse(&tokenize("1,$*5 ---").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Command::InsertEvery {
+                start: None,
+                end: None,
+                interval: 3,
+                text: "".into(),
+            },
+            parse(&tokenize("*3").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("*").unwrap()).is_err());
+        assert!(parse(&tokenize("*0").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_address_arithmetic() {
+        assert_eq!(
+            Address::Expr(Box::new(Address::LastLine), -2),
+            parse_address("$-2").unwrap()
+        );
+        assert_eq!(Address::Offset(3), parse_address(".+3").unwrap());
+        assert_eq!(Address::Offset(1), parse_address("+").unwrap());
+        assert_eq!(Address::Offset(-2), parse_address("--").unwrap());
+        assert_eq!(Address::Offset(1), parse_address(".+2-1").unwrap());
+        assert_eq!(Address::CurrentLine, parse_address(".").unwrap());
+        assert_eq!(
+            Address::Expr(Box::new(Address::Numbered(1)), 5),
+            parse_address("1+5").unwrap()
+        );
+        assert_eq!(
+            Address::Expr(Box::new(Address::Numbered(5)), -1),
+            parse_address("5-").unwrap()
+        );
+        assert_eq!(Address::Numbered(3), parse_address("3+1-1").unwrap());
+
+        assert_eq!(
+            Command::Print {
+                start: Some(Address::Offset(2)),
+                end: Some(Address::Offset(5)),
+            },
+            parse(&tokenize(".+2,.+5p").unwrap()).unwrap()
+        );
+
+        assert_eq!(
+            Address::Expr(Box::new(Address::ForwardSearch("re".into())), 2),
+            parse_address("/re/+2").unwrap()
+        );
+        assert_eq!(
+            Address::Expr(Box::new(Address::BackwardSearch("a-b".into())), -1),
+            parse_address("?a-b?-").unwrap()
+        );
+        assert_eq!(Address::Mark('a', -1), parse_address("'a-1").unwrap());
+        assert_eq!(
+            Command::Print {
+                start: Some(Address::Offset(3)),
+                end: Some(Address::Expr(Box::new(Address::LastLine), -3)),
+            },
+            parse(&tokenize(".+3,$-3p").unwrap()).unwrap()
+        );
+
+        assert!(parse_address("$+x").is_err());
+        assert!(parse_address("/re/x").is_err());
+        assert!(parse_address("1$").is_err());
+    }
+
+    #[test]
+    fn parse_interleave() {
+        assert_eq!(
+            Command::Interleave {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::Numbered(3)),
+                other_start: Address::Numbered(5),
+                other_end: Address::LastLine,
+            },
+            parse(&tokenize("1,3Z5,$").unwrap()).unwrap()
+        );
+
+        assert!(parse(&tokenize("1,3Z").unwrap()).is_err());
     }
 }
@@ -1,76 +1,360 @@
-use std::fs::File;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{self, BufReader, SeekFrom};
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
-use commands::{Action, Command, Mode};
+use commands::{
+    Action, Address, BlockStyle, CheckFormat, ClipFormat, Command, LineEnding, LineFormat, Mode,
+    NoMatch,
+};
 use failure;
 use parser;
-use tokenizer;
+use tokenizer::{self, Token};
+
+/// Modification time and size of a file, used to detect changes on disk.
+pub type FileStat = (Option<SystemTime>, u64);
+
+pub fn file_stat(path: &str) -> Option<FileStat> {
+    fs::metadata(path)
+        .ok()
+        .map(|m| (m.modified().ok(), m.len()))
+}
+
+/// Read all lines from `reader`, naming it `name` in errors.
+fn read_lines<R: BufRead>(reader: R, name: &str) -> Result<Vec<String>, failure::Error> {
+    reader
+        .lines()
+        .collect::<Result<_, _>>()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::InvalidData => format_err!("Cannot open {}: invalid UTF-8", name),
+            _ => err.into(),
+        })
+}
+
+/// The line ending of the first line of `contents`, and whether other lines end differently.
+///
+/// Lines are read without their `\r`, so with mixed line endings,
+/// all lines are written back with the ending of the first one.
+fn line_endings(contents: &[u8]) -> (LineEnding, bool) {
+    let mut first = None;
+    let mut mixed = false;
+    for line in contents.split_inclusive(|&b| b == b'\n') {
+        if line.ends_with(b"\n") {
+            let ending = if line.ends_with(b"\r\n") {
+                LineEnding::CrLf
+            } else {
+                LineEnding::Lf
+            };
+            match first {
+                None => first = Some(ending),
+                Some(first) => mixed |= first != ending,
+            }
+        }
+    }
+    (first.unwrap_or(LineEnding::Lf), mixed)
+}
+
+/// The lines that differ between the buffers `old` and `new`, as the index where they
+/// start, the number of old lines and the number of new ones.
+fn changed_lines(old: &[String], new: &[String]) -> (usize, usize, usize) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    (
+        prefix,
+        old.len() - prefix - suffix,
+        new.len() - prefix - suffix,
+    )
+}
+
+/// Whether `line` is a `g` or `v` command, which can go on over several lines.
+fn is_global(line: &str) -> bool {
+    tokenizer::tokenize(line.trim())
+        .map(|tokens| {
+            tokens
+                .iter()
+                .any(|token| *token == Token::Command('g') || *token == Token::Command('v'))
+        })
+        .unwrap_or(false)
+}
 
 #[derive(Debug)]
 pub struct Red {
     prompt: String,
+    /// Show the prompt, toggled with `P`
+    pub prompt_enabled: bool,
+    /// Explain every `?` right away, toggled with `H`
+    pub help_verbose: bool,
     pub current_line: usize,
     pub data: Vec<String>,
     pub mode: Mode,
     pub path: Option<String>,
     pub dirty: bool,
+    /// Whether the last line ends with a newline when written.
+    /// Only unset for a file loaded without one, so it's written back the same way.
+    pub final_newline: bool,
+    /// Line ending used when writing, taken from the loaded file
+    pub line_ending: LineEnding,
     pub last_error: Option<String>,
+    /// The last warning printed to standard error, like about mixed line endings in a loaded file
+    pub warning: Option<String>,
+    pub safe_write: bool,
+    pub disk_stat: Option<FileStat>,
+    pub max_col: usize,
+    /// Width paragraphs are wrapped to by `jw`
+    pub wrap_width: usize,
+    /// Number of lines printed by `z`, changed by giving it a count.
+    /// By default, a screenful of the terminal.
+    pub window: Option<usize>,
+    pub clip_format: ClipFormat,
+    pub check_format: Option<CheckFormat>,
+    /// Set when the user interrupts a long-running command
+    pub interrupted: Arc<AtomicBool>,
+    pub registers: HashMap<char, Vec<String>>,
+    pub tab_width: usize,
+    /// Number shown for the first line, for buffers that are a fragment of a larger file
+    pub line_offset: usize,
+    /// All successfully dispatched lines, in both command and input mode
+    pub history: Vec<String>,
+    /// Reject commands that change the buffer
+    pub read_only: bool,
+    /// Number of bytes of the file already read into the buffer when following it
+    pub follow_offset: u64,
+    pub block_style: BlockStyle,
+    /// Print the current line after every command that changes the buffer
+    pub auto_print: bool,
+    /// The buffer as it was last loaded from or saved to the file
+    pub original: Vec<String>,
+    /// Lines labeled with the `k` command
+    pub marks: HashMap<char, usize>,
+    /// Report how long each command took to standard error
+    pub time: bool,
+    /// Buffer, current line and modification state before the last change
+    pub undo_buffer: Option<(Vec<String>, usize, bool)>,
+    /// Don't print byte counts
+    pub silent: bool,
+    /// Print the path and number of lines written
+    pub verbose: bool,
+    /// Width long lines are broken at when printed with `p`, if enabled
+    pub soft_wrap: Option<usize>,
+    /// Set after `q` warned about unsaved changes, so that a second `q` quits
+    pub quit_warned: bool,
+    /// Pattern, replacement and flags of the last successful substitution
+    pub last_subst: Option<(String, String, String)>,
+    /// The last pattern used by a search address, `s`, `g` or `G`, repeated by `//`
+    pub last_pattern: Option<String>,
+    /// Lines entered in the last completed input mode
+    pub last_input: Vec<String>,
+    /// How to print the current line once the current input mode ends, as after `ap`
+    pub input_print: Option<LineFormat>,
+    /// The last shell command run, repeated by `!!`
+    pub last_shell: Option<String>,
+    /// Lines still to visit by the running interactive global command, the next one last
+    pub global_lines: Vec<usize>,
+    /// The last command run by an interactive global command, repeated with `&`
+    pub last_global_command: Option<String>,
+    /// The buffer before the running interactive global command, `u` undoes all of it at once
+    pub global_snapshot: Option<(Vec<String>, usize, bool)>,
+    /// Lines entered in the current input mode
+    input: Vec<String>,
+    /// A global command read so far, while its command list goes on with a trailing backslash
+    command_list: Option<String>,
+    /// Collects the lines printed by `p`, `n`, `l` and print suffixes instead of standard output
+    pub output: Option<Vec<u8>>,
 }
 
 impl Red {
     pub fn new(prompt: String, path: Option<String>) -> Red {
-        let (path, data) = match path {
-            None => (None, vec![]),
-            Some(path) => {
-                let data = match File::open(&path) {
-                    Ok(file) => {
-                        let reader = BufReader::new(file);
-                        reader.lines().map(|l| l.unwrap()).collect()
-                    }
-                    Err(_) => vec![],
-                };
-                (Some(path), data)
-            }
-        };
-
-        let len = data.len();
-        Red {
+        let mut ed = Red {
+            prompt_enabled: !prompt.is_empty(),
+            help_verbose: false,
             prompt,
-            data,
-            path,
-            current_line: len,
+            data: vec![],
+            path: None,
+            current_line: 0,
             mode: Mode::Command,
             dirty: false,
+            final_newline: true,
+            line_ending: LineEnding::Lf,
             last_error: None,
+            warning: None,
+            safe_write: false,
+            disk_stat: None,
+            max_col: 80,
+            wrap_width: 72,
+            window: None,
+            clip_format: ClipFormat::Raw,
+            check_format: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            registers: HashMap::new(),
+            tab_width: 8,
+            line_offset: 1,
+            history: vec![],
+            read_only: false,
+            follow_offset: 0,
+            block_style: BlockStyle::Braces,
+            auto_print: false,
+            original: vec![],
+            marks: HashMap::new(),
+            time: false,
+            undo_buffer: None,
+            silent: false,
+            verbose: false,
+            soft_wrap: None,
+            last_subst: None,
+            last_pattern: None,
+            quit_warned: false,
+            last_input: vec![],
+            input_print: None,
+            last_shell: None,
+            global_lines: vec![],
+            last_global_command: None,
+            global_snapshot: None,
+            input: vec![],
+            command_list: None,
+            output: None,
+        };
+
+        if path.as_ref().map(|p| p == "-").unwrap_or(false) {
+            // Read the buffer from a pipe, there is no file to write it back to
+            let stdin = io::stdin();
+            let result = read_lines(stdin.lock(), "standard input");
+            match result {
+                Ok(data) => ed.replace_data(data),
+                Err(err) => ed.last_error = Some(err.to_string()),
+            }
+        } else if let Some(path) = path {
+            if Path::new(&path).exists() {
+                // The path of a file that can't be read is not kept,
+                // so writing the empty buffer doesn't clobber it
+                if let Err(err) = ed.load_file(path) {
+                    ed.last_error = Some(err.to_string());
+                }
+            } else {
+                ed.path = Some(path);
+            }
         }
+        ed
     }
 
     pub fn load_data(&self, path: &str) -> Result<Vec<String>, failure::Error> {
         let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let data = reader.lines().map(|l| l.unwrap()).collect();
-        Ok(data)
+        read_lines(BufReader::new(file), path)
     }
 
     pub fn load_file(&mut self, path: String) -> Result<(), failure::Error> {
-        let data = self.load_data(&path)?;
-        let len = data.len();
+        // Read the file once, its lines and how they end come from the same contents
+        let contents = fs::read(&path)?;
+        let data = read_lines(&contents[..], &path)?;
+        let final_newline = contents.is_empty() || contents.ends_with(b"\n");
+        let (line_ending, mixed) = line_endings(&contents);
+        self.replace_data(data);
+        self.final_newline = final_newline;
+        self.line_ending = line_ending;
+        self.warning = None;
+        if mixed {
+            let ending = match line_ending {
+                LineEnding::Lf => "LF",
+                LineEnding::CrLf => "CRLF",
+            };
+            self.warn(format!(
+                "Mixed line endings, writing all lines with {}",
+                ending
+            ));
+        }
+        self.disk_stat = file_stat(&path);
         self.path = Some(path);
-        self.data = data;
-        self.current_line = len;
 
         Ok(())
     }
 
+    /// Print `message` to standard error, the operation goes on regardless.
+    fn warn(&mut self, message: String) {
+        eprintln!("warning: {}", message);
+        self.warning = Some(message);
+    }
+
+    /// Replace the buffer with `data` that's not read from a file, like the output of a command.
+    ///
+    /// The current line is set to the last line. Undo and marks start over.
+    pub fn replace_data(&mut self, data: Vec<String>) {
+        let (old, new) = (self.data.len(), data.len());
+        self.lines_replaced(0, old, new);
+        self.current_line = data.len();
+        self.original = data.clone();
+        self.data = data;
+        self.undo_buffer = None;
+        self.marks.clear();
+        self.final_newline = true;
+        self.line_ending = LineEnding::Lf;
+    }
+
+    /// Append the complete lines added to the file since it was last read, like `tail -f`.
+    ///
+    /// If the file shrank, it is read from the start again.
+    /// Returns the newly added lines.
+    pub fn read_appended(&mut self) -> Result<Vec<String>, failure::Error> {
+        let mut file = match self.path {
+            None => return Err(format_err!("No current filename")),
+            Some(ref path) => File::open(path)?,
+        };
+
+        let len = file.metadata()?.len();
+        if len < self.follow_offset {
+            debug!("File truncated, reading from the start");
+            self.follow_offset = 0;
+            self.data.clear();
+        }
+
+        file.seek(SeekFrom::Start(self.follow_offset))?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+
+        // Leave incomplete lines for the next time
+        let end = match buf.iter().rposition(|&b| b == b'\n') {
+            None => return Ok(vec![]),
+            Some(idx) => idx + 1,
+        };
+        let lines: Vec<String> = String::from_utf8_lossy(&buf[..end])
+            .lines()
+            .map(|l| l.to_string())
+            .collect();
+
+        self.follow_offset += end as u64;
+        self.data.extend(lines.iter().cloned());
+        self.current_line = self.lines();
+        Ok(lines)
+    }
+
     pub fn data_size(&self) -> usize {
-        self.data.iter().map(|l| l.len() + 1).sum()
+        let ending = self.line_ending.as_str().len();
+        let size: usize = self.data.iter().map(|l| l.len() + ending).sum();
+        if self.final_newline || size == 0 {
+            size
+        } else {
+            size - ending
+        }
     }
 
     pub fn lines(&self) -> usize {
         self.data.len()
     }
 
+    /// The number shown to the user for a line, taking the line offset into account.
+    pub fn display_line_number(&self, line: usize) -> usize {
+        line + self.line_offset - 1
+    }
+
     pub fn set_line(&mut self, line: usize) -> Result<(), failure::Error> {
         if line < 1 || line > self.lines() {
             Err(format_err!("Invalid address"))
@@ -88,6 +372,67 @@ impl Red {
         }
     }
 
+    /// Run the commands read from `input`, including the text of input mode,
+    /// until a command quits or the input ends.
+    ///
+    /// The first failing or unknown command stops the script with an error
+    /// naming its line.
+    pub fn run_script<R: BufRead>(&mut self, input: R) -> Result<(), failure::Error> {
+        for (n, line) in input.lines().enumerate() {
+            let line = line?;
+            match self.dispatch(&line) {
+                Ok(Action::Quit) => break,
+                Ok(Action::Continue) => {}
+                Err(err) => {
+                    self.last_error = Some(err.to_string());
+                    return Err(format_err!("line {}: {}", n + 1, err));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the `s///` expressions in a file, one per line, to the whole buffer.
+    ///
+    /// Expressions that match nothing are skipped like in sed.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn apply_sed_file(&mut self, path: &str) -> Result<(), failure::Error> {
+        if self.read_only {
+            return Err(format_err!("Buffer is read-only"));
+        }
+        let script = self.load_data(path)?;
+        for (n, line) in script.iter().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let arg = match self.parse_command(line) {
+                Ok(Command::Substitute {
+                    start: None,
+                    end: None,
+                    arg,
+                }) => arg,
+                _ => return Err(format_err!("{}:{}: Invalid substitution", path, n + 1)),
+            };
+            if self.data.is_empty() {
+                continue;
+            }
+
+            let command = Command::Substitute {
+                start: Some(Address::Numbered(1)),
+                end: Some(Address::LastLine),
+                arg,
+            };
+            match command.execute(self) {
+                Err(ref e) if e.downcast_ref::<NoMatch>().is_some() => {}
+                Err(e) => return Err(format_err!("{}:{}: {}", path, n + 1, e)),
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    }
+
     fn parse_command(&self, line: &str) -> Result<Command, failure::Error> {
         let tokens = tokenizer::tokenize(line)?;
         debug!("tokens: {:#?}", tokens);
@@ -98,16 +443,169 @@ impl Red {
     }
 
     fn dispatch_command(&mut self, line: &str) -> Result<Action, failure::Error> {
+        // Forget about interrupts that happened while no command was running
+        self.interrupted.store(false, Ordering::SeqCst);
         let command = self.parse_command(line.trim())?;
-        command.execute(self)
+        if self.read_only && command.is_mutating() {
+            return Err(format_err!("Buffer is read-only"));
+        }
+        // Recording the session shouldn't end up in the recording itself
+        let record = !matches!(command, Command::Record { .. });
+
+        // Only a `q` right after the warning quits
+        match command {
+            Command::Quit { .. } | Command::WriteQuit { .. } => {}
+            _ => self.quit_warned = false,
+        }
+
+        let mutating = command.is_mutating();
+        // Remember the state before a change, `u` brings it back.
+        // Undo keeps its own state and a newly loaded file can't be undone.
+        let snapshot = match command {
+            Command::Undo | Command::Edit { .. } => None,
+            _ if mutating => Some((self.data.clone(), self.current_line, self.dirty)),
+            _ => None,
+        };
+        let started = Instant::now();
+        let result = command.execute(self);
+        if self.time {
+            eprintln!("{}: {:.3?}", line.trim(), started.elapsed());
+        }
+        // A command failing partway may have changed the buffer already,
+        // keep undo in step with it
+        match snapshot {
+            Some(snapshot) if result.is_ok() || snapshot.0 != self.data => {
+                self.undo_buffer = Some(snapshot)
+            }
+            _ => {}
+        }
+        let action = result?;
+        if record {
+            self.history.push(line.into());
+        }
+
+        // Commands switching to input mode print nothing until the input is done
+        let current = self.current_line;
+        if mutating
+            && self.auto_print
+            && self.mode == Mode::Command
+            && self.get_line(current).is_some()
+        {
+            Command::Print {
+                start: None,
+                end: None,
+            }
+            .execute(self)?;
+        }
+        Ok(action)
+    }
+
+    /// Run the command read for the current line of an interactive global command,
+    /// then move on to the next line.
+    ///
+    /// An empty line skips the current line, `&` repeats the last command.
+    /// An error ends the global command.
+    fn dispatch_global(&mut self, line: &str) -> Result<Action, failure::Error> {
+        let command = match line.trim() {
+            "" => None,
+            "&" => match self.last_global_command.clone() {
+                None => {
+                    self.abort_global();
+                    return Err(format_err!("No previous command"));
+                }
+                Some(command) => Some(command),
+            },
+            command => Some(command.to_string()),
+        };
+
+        if let Some(command) = command {
+            match self.parse_command(&command) {
+                Ok(Command::Global { .. })
+                | Ok(Command::InteractiveGlobal { .. })
+                | Ok(Command::Insert { .. })
+                | Ok(Command::Append { .. })
+                | Ok(Command::Change { .. }) => {
+                    self.abort_global();
+                    return Err(format_err!("Invalid command"));
+                }
+                _ => {}
+            }
+
+            self.mode = Mode::Command;
+            let action = match self.dispatch_command(&command) {
+                Ok(action) => action,
+                Err(err) => {
+                    self.abort_global();
+                    return Err(err);
+                }
+            };
+            self.last_global_command = Some(command);
+            if action == Action::Quit {
+                self.abort_global();
+                return Ok(action);
+            }
+        }
+
+        self.next_global_line()
+    }
+
+    /// Print the next line of the interactive global command to run a command on,
+    /// or return to command mode after the last one.
+    pub fn next_global_line(&mut self) -> Result<Action, failure::Error> {
+        match self.global_lines.pop() {
+            None => {
+                self.finish_global();
+                Ok(Action::Continue)
+            }
+            Some(line) => {
+                self.mode = Mode::Global;
+                self.current_line = line;
+                Command::Print {
+                    start: None,
+                    end: None,
+                }
+                .execute(self)
+            }
+        }
+    }
+
+    fn abort_global(&mut self) {
+        self.global_lines.clear();
+        self.finish_global();
+    }
+
+    /// Return to command mode after an interactive global command.
+    ///
+    /// The changes made on all its lines are undone by a single `u`.
+    fn finish_global(&mut self) {
+        self.mode = Mode::Command;
+        if let Some(snapshot) = self.global_snapshot.take() {
+            if snapshot.0 != self.data {
+                self.undo_buffer = Some(snapshot);
+            }
+        }
     }
 
     fn dispatch_input(&mut self, line: &str) -> Result<Action, failure::Error> {
+        self.history.push(line.into());
         if line == "." {
             self.mode = Mode::Command;
+            if !self.input.is_empty() {
+                self.last_input = mem::take(&mut self.input);
+            }
+            let print = self.input_print.take();
+            Command::print_current(self, print)?;
             return Ok(Action::Continue);
         }
+        self.input.push(line.into());
+
+        self.insert_line(line);
 
+        Ok(Action::Continue)
+    }
+
+    /// Insert a line of input after the current line, which becomes the inserted one.
+    pub fn insert_line(&mut self, line: &str) {
         let idx = self.current_line;
         debug!("Inserting line at {}", idx);
         if self.data.is_empty() {
@@ -115,23 +613,115 @@ impl Red {
         } else {
             self.data.insert(idx, line.into());
         }
+        self.lines_replaced(idx, 0, 1);
         self.current_line += 1;
         self.dirty = true;
+    }
 
-        Ok(Action::Continue)
+    /// Move marks and the lines still to visit by a global command along with their lines,
+    /// after the `removed` lines from index `at` were replaced by `inserted` lines.
+    ///
+    /// Lines replaced one for one, e.g. by `s`, keep their number. Those on lines that
+    /// were removed without a replacement are dropped.
+    pub fn lines_replaced(&mut self, at: usize, removed: usize, inserted: usize) {
+        let kept = at + cmp::min(removed, inserted);
+        self.follow_lines(|line| {
+            if line <= kept {
+                Some(line)
+            } else if line <= at + removed {
+                None
+            } else {
+                Some(line - removed + inserted)
+            }
+        });
+    }
+
+    /// Move marks and the lines still to visit by a global command along with their lines,
+    /// after `count` lines from index `from` were moved to index `to` of the buffer without them.
+    pub fn lines_moved(&mut self, from: usize, count: usize, to: usize) {
+        self.follow_lines(|line| {
+            if line > from && line <= from + count {
+                return Some(to + line - from);
+            }
+            let line = if line > from + count {
+                line - count
+            } else {
+                line
+            };
+            Some(if line > to { line + count } else { line })
+        });
+    }
+
+    /// Change the line numbers of marks and of the lines still to visit by a global command
+    /// to `moved` ones, dropping those mapped to `None`.
+    fn follow_lines<F: Fn(usize) -> Option<usize>>(&mut self, moved: F) {
+        self.marks = self
+            .marks
+            .drain()
+            .filter_map(|(label, line)| moved(line).map(|line| (label, line)))
+            .collect();
+        self.global_lines = self
+            .global_lines
+            .iter()
+            .filter_map(|&line| moved(line))
+            .collect();
+    }
+
+    /// Move marks and the lines still to visit by a global command along with their lines,
+    /// after the buffer changed from `old` in one place, e.g. by `u`.
+    pub fn data_changed(&mut self, old: &[String]) {
+        let (at, removed, inserted) = changed_lines(old, &self.data);
+        self.lines_replaced(at, removed, inserted);
     }
 
     pub fn dispatch(&mut self, line: &str) -> Result<Action, failure::Error> {
         match self.mode {
-            Mode::Command => self.dispatch_command(line),
+            Mode::Command => match self.collect_command_list(line) {
+                None => Ok(Action::Continue),
+                Some(command) => self.dispatch_command(&command),
+            },
             Mode::Input => self.dispatch_input(line),
+            Mode::Global => self.dispatch_global(line),
+        }
+    }
+
+    /// Collect the lines of a global command continued with a trailing backslash,
+    /// as in `g/re/s/a/b/\` followed by `p`.
+    ///
+    /// Returns the command to run once it is complete.
+    fn collect_command_list(&mut self, line: &str) -> Option<String> {
+        let continued = line.ends_with('\\');
+        let command = match self.command_list.take() {
+            Some(mut command) => {
+                command.push('\n');
+                command.push_str(line);
+                command
+            }
+            None if continued && is_global(line) => line.to_string(),
+            None => return Some(line.to_string()),
+        };
+        if continued {
+            self.command_list = Some(command);
+            None
+        } else {
+            Some(command)
+        }
+    }
+
+    /// What to print when a command failed: a `?`, followed by the reason with `H` enabled.
+    pub fn error_report(&self) -> String {
+        match self.last_error {
+            Some(ref err) if self.help_verbose => format!("?\n{}", err),
+            _ => "?".into(),
         }
     }
 
     pub fn prompt(&self) -> &str {
         match self.mode {
-            Mode::Command => &self.prompt,
-            Mode::Input => "",
+            // Without `-p`, `P` shows ed's default prompt
+            Mode::Command if self.prompt_enabled && self.prompt.is_empty() => "*",
+            Mode::Command if self.prompt_enabled => &self.prompt,
+            _ => "",
         }
     }
 }
@@ -139,6 +729,52 @@ impl Red {
 #[cfg(test)]
 mod test {
     use super::*;
+    use commands::Address;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|line| line.to_string()).collect()
+    }
+
+    /// An editor with `text` appended to its buffer, like after `a` and `.`.
+    fn editor(text: &[&str]) -> Red {
+        let mut ed = Red::new("".into(), None);
+        ed.dispatch("a").unwrap();
+        for line in text {
+            ed.dispatch(line).unwrap();
+        }
+        ed.dispatch(".").unwrap();
+        ed
+    }
+
+    /// What the editor printed since the last call, it must be collecting its output.
+    fn printed(ed: &mut Red) -> String {
+        String::from_utf8(ed.output.replace(vec![]).unwrap()).unwrap()
+    }
+
+    /// A file in the temporary directory, unique to the test and the process,
+    /// that is removed once the test is done.
+    struct TempFile {
+        path: String,
+    }
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let name = format!("red-{}-{}", process::id(), name);
+            let path = ::std::env::temp_dir().join(name);
+            TempFile {
+                path: path.to_str().unwrap().to_string(),
+            }
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
 
     #[test]
     fn simple_edits() {
@@ -192,15 +828,111 @@ mod test {
     }
 
     #[test]
-    fn change_line() {
+    fn quit_modified_buffer() {
         let mut ed = Red::new("".into(), None);
+        assert_eq!(Action::Quit, ed.dispatch("q").unwrap());
 
         ed.dispatch("a").unwrap();
-        ed.dispatch("Line 1").unwrap();
-        ed.dispatch("Line 2").unwrap();
-        ed.dispatch("Line 3").unwrap();
-        ed.dispatch("Line 4").unwrap();
+        ed.dispatch("text").unwrap();
         ed.dispatch(".").unwrap();
+        let err = ed.dispatch("q").unwrap_err();
+        assert_eq!("Warning: buffer modified", err.to_string());
+        assert!(ed.dirty);
+        assert_eq!(Action::Quit, ed.dispatch("q").unwrap());
+
+        // Another command in between asks again
+        ed.dispatch("p").unwrap();
+        assert!(ed.dispatch("q").is_err());
+        ed.dispatch("p").unwrap();
+        assert!(ed.dispatch("q").is_err());
+        assert_eq!(Action::Quit, ed.dispatch("q").unwrap());
+
+        assert_eq!(Action::Quit, ed.dispatch("Q").unwrap());
+    }
+
+    #[test]
+    fn write_and_quit() {
+        let file = TempFile::new("wq-test.txt");
+        let path = file.path.clone();
+
+        let mut ed = editor(&["text"]);
+        // Without a filename nothing is written and the editor stays
+        let err = ed.dispatch("wq").unwrap_err();
+        assert_eq!("No current filename", err.to_string());
+        assert!(ed.dirty);
+
+        assert_eq!(Action::Quit, ed.dispatch(&format!("wq {}", path)).unwrap());
+        assert!(!ed.dirty);
+        assert_eq!("text\n", fs::read_to_string(&path).unwrap());
+
+        // Writing only some of the lines warns like `q`, a second `wq` quits
+        ed.dispatch("a").unwrap();
+        ed.dispatch("more").unwrap();
+        ed.dispatch(".").unwrap();
+        let err = ed.dispatch(&format!("1wq {}", path)).unwrap_err();
+        assert_eq!("Warning: buffer modified", err.to_string());
+        assert!(ed.dirty);
+        assert_eq!(Action::Quit, ed.dispatch(&format!("1wq {}", path)).unwrap());
+        assert_eq!("text\n", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn set_filename() {
+        let file = TempFile::new("filename-test.txt");
+        let path = file.path.clone();
+
+        let mut ed = Red::new("".into(), None);
+        assert!(ed.dispatch("f").is_err());
+        ed.dispatch("a").unwrap();
+        ed.dispatch("text").unwrap();
+        ed.dispatch(".").unwrap();
+
+        ed.dispatch(&format!("f {}", path)).unwrap();
+        assert_eq!(Some(&path), ed.path.as_ref());
+        assert!(ed.dirty);
+        ed.dispatch("f").unwrap();
+
+        ed.dispatch("w").unwrap();
+        assert_eq!("text\n", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn toggle_prompt() {
+        let mut ed = Red::new("> ".into(), None);
+        assert_eq!("> ", ed.prompt());
+        ed.dispatch("P").unwrap();
+        assert_eq!("", ed.prompt());
+        ed.dispatch("P").unwrap();
+        assert_eq!("> ", ed.prompt());
+
+        let mut ed = Red::new("".into(), None);
+        assert!(!ed.prompt_enabled);
+        assert_eq!("", ed.prompt());
+        ed.dispatch("P").unwrap();
+        assert_eq!("*", ed.prompt());
+    }
+
+    #[test]
+    fn toggle_help() {
+        let mut ed = Red::new("".into(), None);
+        let err = ed.dispatch("5p").unwrap_err();
+        ed.last_error = Some(err.to_string());
+        assert_eq!("?", ed.error_report());
+
+        ed.dispatch("H").unwrap();
+        assert!(ed.help_verbose);
+        assert_eq!("?\nInvalid address", ed.error_report());
+        ed.dispatch("H").unwrap();
+        assert_eq!("?", ed.error_report());
+
+        // Every `?` has a reason, also for an empty command past the last line
+        let err = ed.dispatch("").unwrap_err();
+        assert_eq!("Invalid address", err.to_string());
+    }
+
+    #[test]
+    fn change_line() {
+        let mut ed = editor(&["Line 1", "Line 2", "Line 3", "Line 4"]);
 
         {
             ed.dispatch("2c").unwrap();
@@ -220,4 +952,1016 @@ mod test {
             assert_eq!(vec!["hello", "world", "Line 3", "Line 4"], &data[..]);
         }
     }
+
+    #[test]
+    fn comment_with_address() {
+        let mut ed = editor(&["Line 1", "Line 2", "Line 3"]);
+
+        ed.dispatch("# nothing happens").unwrap();
+        assert_eq!(3, ed.current_line);
+
+        ed.dispatch("2#this line is interesting").unwrap();
+        assert_eq!(2, ed.current_line);
+        assert_eq!(3, ed.lines());
+        assert!(ed.dispatch("12#note").is_err());
+    }
+
+    #[test]
+    fn silent_jump() {
+        let mut ed = editor(&["Line 1", "Line 2"]);
+
+        ed.dispatch("1;").unwrap();
+        assert_eq!(1, ed.current_line);
+        assert!(ed.dispatch("5;").is_err());
+        assert_eq!(1, ed.current_line);
+    }
+
+    #[test]
+    fn safe_write_detects_changes_on_disk() {
+        let file = TempFile::new("safe-write-test.txt");
+        let path = file.path.clone();
+        fs::write(&path, "Line 1\n").unwrap();
+
+        let mut ed = Red::new("".into(), Some(path.clone()));
+        ed.safe_write = true;
+        ed.dispatch("a").unwrap();
+        ed.dispatch("Line 2").unwrap();
+        ed.dispatch(".").unwrap();
+
+        // Someone else changes the file underneath us
+        fs::write(&path, "Line 1\nSomething else\nentirely\n").unwrap();
+        assert!(ed.dispatch("w").is_err());
+        // Re-issuing the write overrides the check
+        ed.dispatch("w").unwrap();
+        assert_eq!("Line 1\nLine 2\n", fs::read_to_string(&path).unwrap());
+
+        fs::write(&path, "Changed again, with different size\n").unwrap();
+        ed.dispatch("w!").unwrap();
+        assert_eq!("Line 1\nLine 2\n", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn append_to_file() {
+        let file = TempFile::new("append-test.txt");
+        let path = file.path.clone();
+
+        let mut ed = editor(&["one", "two"]);
+        ed.dispatch(&format!("w {}", path)).unwrap();
+        assert_eq!("one\ntwo\n", fs::read_to_string(&path).unwrap());
+
+        ed.dispatch("1d").unwrap();
+        assert!(ed.dirty);
+        ed.dispatch("W").unwrap();
+        assert_eq!("one\ntwo\ntwo\n", fs::read_to_string(&path).unwrap());
+        assert!(!ed.dirty);
+
+        ed.dispatch(&format!("1w >>{}", path)).unwrap();
+        assert_eq!("one\ntwo\ntwo\ntwo\n", fs::read_to_string(&path).unwrap());
+
+        // Appending to a log doesn't make it the current file
+        let log_file = TempFile::new("append-log-test.txt");
+        let log = log_file.path.clone();
+        ed.dispatch(&format!("W {}", log)).unwrap();
+        ed.dispatch(&format!("w >>{}", log)).unwrap();
+        assert_eq!(Some(&path), ed.path.as_ref());
+        ed.dispatch("w").unwrap();
+        assert_eq!("two\n", fs::read_to_string(&path).unwrap());
+        assert_eq!("two\ntwo\n", fs::read_to_string(&log).unwrap());
+
+        let mut ed = editor(&["one"]);
+        ed.dispatch(&format!("W {}", log)).unwrap();
+        assert_eq!(None, ed.path);
+
+        // Writing elsewhere keeps the current filename as well
+        let other_file = TempFile::new("append-other-test.txt");
+        ed.dispatch(&format!("w {}", path)).unwrap();
+        ed.dispatch(&format!("w {}", other_file.path)).unwrap();
+        assert_eq!(Some(&path), ed.path.as_ref());
+    }
+
+    #[test]
+    fn marks_follow_their_lines() {
+        let mut ed = editor(&["one", "two", "three", "four"]);
+        ed.dispatch("3ka").unwrap();
+        ed.dispatch("4kb").unwrap();
+
+        // Inserting before the marked line
+        ed.dispatch("1i").unwrap();
+        ed.dispatch("zero").unwrap();
+        ed.dispatch(".").unwrap();
+        ed.dispatch("'a").unwrap();
+        assert_eq!(Some("three"), ed.get_line(ed.current_line));
+
+        // Deleting before it, and changing it in place
+        ed.dispatch("1,2d").unwrap();
+        ed.dispatch("'as/three/3/").unwrap();
+        ed.dispatch("'a").unwrap();
+        assert_eq!(Some("3"), ed.get_line(ed.current_line));
+        assert_eq!(2, ed.current_line);
+
+        // Moving it along
+        ed.dispatch("'am0").unwrap();
+        ed.dispatch("'a").unwrap();
+        assert_eq!(1, ed.current_line);
+
+        // The mark goes away with its line
+        ed.dispatch("'bd").unwrap();
+        let err = ed.dispatch("'b").unwrap_err();
+        assert_eq!("Invalid mark", err.to_string());
+        assert!(ed.dispatch("'a").is_ok());
+    }
+
+    #[test]
+    fn marks_follow_duplicate_lines() {
+        let mut ed = editor(&["x", "y", "x"]);
+        ed.dispatch("1ka").unwrap();
+        ed.dispatch("3kb").unwrap();
+
+        // Both lines read the same, the marks still stay with their own line
+        ed.dispatch("3m0").unwrap();
+        assert_eq!(Some(&2), ed.marks.get(&'a'));
+        assert_eq!(Some(&1), ed.marks.get(&'b'));
+
+        ed.dispatch("1t1").unwrap();
+        ed.dispatch("1d").unwrap();
+        assert_eq!(vec!["x", "x", "y"], ed.data);
+        assert_eq!(Some(&2), ed.marks.get(&'a'));
+        assert_eq!(None, ed.marks.get(&'b'));
+    }
+
+    #[test]
+    fn mark_addresses() {
+        let mut ed = editor(&["one", "two", "three", "four"]);
+        ed.dispatch("2ka").unwrap();
+        ed.dispatch("4kb").unwrap();
+
+        // Marks work as the target of `t` and `m`, and in ranges
+        ed.dispatch("1t'b").unwrap();
+        assert_eq!(vec!["one", "two", "three", "four", "one"], ed.data);
+        ed.dispatch("'a;+1d").unwrap();
+        assert_eq!(vec!["one", "four", "one"], ed.data);
+        ed.dispatch("1m'b").unwrap();
+        assert_eq!(vec!["four", "one", "one"], ed.data);
+        ed.dispatch("'b").unwrap();
+        assert_eq!(1, ed.current_line);
+        assert!(ed.dispatch("'a").is_err());
+    }
+
+    #[test]
+    fn repeat_substitution() {
+        let mut ed = Red::new("".into(), None);
+        assert!(ed.dispatch("s").is_err());
+
+        ed.dispatch("a").unwrap();
+        for line in &["a a", "a a", "a a", "xa"] {
+            ed.dispatch(line).unwrap();
+        }
+        ed.dispatch(".").unwrap();
+
+        ed.dispatch("1s/a/b/g").unwrap();
+        ed.dispatch("2s").unwrap();
+        assert_eq!(vec!["b b", "b b", "a a", "xa"], ed.data);
+
+        // An empty pattern takes the last one, with a new replacement
+        ed.dispatch("3s//c/").unwrap();
+        assert_eq!("c a", ed.data[2]);
+        ed.dispatch("4s/").unwrap();
+        assert_eq!("xc", ed.data[3]);
+
+        // A failed substitution is not remembered
+        assert!(ed.dispatch("1s/nothing/d/").is_err());
+        ed.dispatch("3s").unwrap();
+        assert_eq!("c c", ed.data[2]);
+
+        // Nothing left to replace, or a remembered pattern that doesn't compile
+        assert_eq!("No match", ed.dispatch("1s").unwrap_err().to_string());
+        ed.last_subst = Some(("(".into(), "d".into(), "".into()));
+        assert_eq!(
+            "Invalid pattern",
+            ed.dispatch("3s").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn run_script() {
+        let mut ed = Red::new("".into(), None);
+        let script = "a\none\ntwo\n.\n1s/one/1/\n$a\nthree\n.\n";
+        ed.run_script(io::Cursor::new(script)).unwrap();
+        assert_eq!(vec!["1", "two", "three"], ed.data);
+
+        // Commands after `q` are not run
+        ed.run_script(io::Cursor::new("1d\nQ\n1d\n")).unwrap();
+        assert_eq!(vec!["two", "three"], ed.data);
+
+        ed.run_script(io::Cursor::new("")).unwrap();
+
+        let err = ed.run_script(io::Cursor::new("1d\n5p\n1d\n")).unwrap_err();
+        assert_eq!("line 2: Invalid address", err.to_string());
+        assert_eq!(vec!["three"], ed.data);
+    }
+
+    #[test]
+    fn read_lines_from_reader() {
+        let input = io::Cursor::new("one\ntwo\n");
+        assert_eq!(vec!["one", "two"], read_lines(input, "input").unwrap());
+
+        let input = io::Cursor::new(b"\xff\n".to_vec());
+        let err = read_lines(input, "standard input").unwrap_err();
+        assert_eq!("Cannot open standard input: invalid UTF-8", err.to_string());
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let file = TempFile::new("latin1-test.txt");
+        let path = file.path.clone();
+        fs::write(&path, b"caf\xe9\n").unwrap();
+
+        let ed = Red::new("".into(), None);
+        let err = ed.load_data(&path).unwrap_err();
+        assert_eq!(
+            format!("Cannot open {}: invalid UTF-8", path),
+            err.to_string()
+        );
+
+        // The editor still starts, without a buffer to overwrite the file with
+        let ed = Red::new("".into(), Some(path.clone()));
+        assert!(ed.data.is_empty());
+        assert_eq!(None, ed.path);
+        assert!(ed.last_error.is_some());
+    }
+
+    #[test]
+    fn keep_crlf_line_endings() {
+        let file = TempFile::new("crlf-test.txt");
+        let path = file.path.clone();
+        fs::write(&path, "one\r\ntwo\r\n").unwrap();
+
+        let mut ed = Red::new("".into(), Some(path.clone()));
+        ed.silent = true;
+        assert_eq!(LineEnding::CrLf, ed.line_ending);
+        assert_eq!(vec!["one", "two"], ed.data);
+        assert_eq!(10, ed.data_size());
+
+        ed.dispatch("2s/two/2/").unwrap();
+        ed.dispatch("w").unwrap();
+        assert_eq!("one\r\n2\r\n", fs::read_to_string(&path).unwrap());
+
+        assert_eq!(None, ed.warning);
+
+        // The first line decides for mixed endings, with a warning
+        fs::write(&path, "one\ntwo\r\n").unwrap();
+        ed.dispatch("e").unwrap();
+        assert_eq!(LineEnding::Lf, ed.line_ending);
+        assert_eq!(vec!["one", "two"], ed.data);
+        assert_eq!(
+            Some("Mixed line endings, writing all lines with LF"),
+            ed.warning.as_deref()
+        );
+        ed.dispatch("w").unwrap();
+        assert_eq!("one\ntwo\n", fs::read_to_string(&path).unwrap());
+
+        // Reading it again, the endings are consistent
+        ed.dispatch("e").unwrap();
+        assert_eq!(None, ed.warning);
+    }
+
+    #[test]
+    fn edit_modified_buffer() {
+        let file = TempFile::new("edit-test.txt");
+        let path = file.path.clone();
+        fs::write(&path, "one\ntwo\n").unwrap();
+
+        let mut ed = Red::new("".into(), Some(path.clone()));
+        ed.dispatch("1d").unwrap();
+        assert!(ed.dirty);
+
+        let err = ed.dispatch("e").unwrap_err();
+        assert_eq!("Warning: buffer modified", err.to_string());
+        assert_eq!(vec!["two"], ed.data);
+
+        ed.dispatch("e!").unwrap();
+        assert_eq!(vec!["one", "two"], ed.data);
+        assert_eq!(2, ed.current_line);
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn write_empty_buffer() {
+        let file = TempFile::new("empty-write-test.txt");
+        let path = file.path.clone();
+
+        let mut ed = editor(&["one", "two"]);
+        ed.silent = true;
+        ed.dispatch(&format!("w {}", path)).unwrap();
+
+        // A bad range leaves the file alone
+        assert!(ed.dispatch("5w").is_err());
+        assert_eq!("one\ntwo\n", fs::read_to_string(&path).unwrap());
+
+        ed.dispatch(",d").unwrap();
+        assert!(ed.dirty);
+        assert!(ed.dispatch("1w").is_err());
+        assert_eq!("one\ntwo\n", fs::read_to_string(&path).unwrap());
+        ed.dispatch("w").unwrap();
+        assert_eq!("", fs::read_to_string(&path).unwrap());
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn keep_missing_final_newline() {
+        let file = TempFile::new("no-newline-test.txt");
+        let path = file.path.clone();
+        let copy_file = TempFile::new("no-newline-copy.txt");
+        let copy = copy_file.path.clone();
+        fs::write(&path, "one\ntwo").unwrap();
+
+        let mut ed = Red::new("".into(), Some(path.clone()));
+        ed.silent = true;
+        assert!(!ed.final_newline);
+        assert_eq!(7, ed.data_size());
+        ed.dispatch(&format!("w {}", copy)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), fs::read(&copy).unwrap());
+
+        // Only the last line of the buffer goes without a newline
+        ed.dispatch(&format!("1w {}", copy)).unwrap();
+        assert_eq!("one\n", fs::read_to_string(&copy).unwrap());
+
+        // A new buffer ends with a newline
+        let mut ed = editor(&["one"]);
+        ed.silent = true;
+        ed.dispatch(&format!("w {}", copy)).unwrap();
+        assert_eq!("one\n", fs::read_to_string(&copy).unwrap());
+    }
+
+    #[test]
+    fn rotate_lines() {
+        let mut ed = editor(&["1", "2", "3", "4", "5", "6"]);
+
+        ed.dispatch("1,5@2").unwrap();
+        assert_eq!(vec!["4", "5", "1", "2", "3", "6"], &ed.data[..]);
+        assert_eq!(5, ed.current_line);
+
+        ed.dispatch("1,5@-2").unwrap();
+        assert_eq!(vec!["1", "2", "3", "4", "5", "6"], &ed.data[..]);
+
+        ed.dispatch("2,3@7").unwrap();
+        assert_eq!(vec!["1", "3", "2", "4", "5", "6"], &ed.data[..]);
+    }
+
+    #[test]
+    fn bare_offsets() {
+        let mut ed = editor(&["1", "2", "3", "4", "5"]);
+        ed.output = Some(vec![]);
+
+        ed.dispatch("3;").unwrap();
+        assert_eq!("", printed(&mut ed));
+        ed.dispatch("+").unwrap();
+        assert_eq!(4, ed.current_line);
+        assert_eq!("4\n", printed(&mut ed));
+        ed.dispatch("--").unwrap();
+        assert_eq!(2, ed.current_line);
+        assert_eq!("2\n", printed(&mut ed));
+        assert!(ed.dispatch("--").is_err());
+        assert_eq!(2, ed.current_line);
+        assert_eq!("", printed(&mut ed));
+    }
+
+    #[test]
+    fn translate_characters() {
+        let mut ed = editor(&["a-b-c", "c;b;a", "untouched"]);
+
+        ed.dispatch("1,2T/abc;/xyz-/").unwrap();
+        assert_eq!(vec!["x-y-z", "z-y-x", "untouched"], &ed.data[..]);
+        assert_eq!(2, ed.current_line);
+
+        assert!(ed.dispatch("T/ab/x/").is_err());
+        assert!(ed.dispatch("T/ab").is_err());
+        assert_eq!(vec!["x-y-z", "z-y-x", "untouched"], &ed.data[..]);
+    }
+
+    #[test]
+    fn interrupted_substitute_keeps_buffer() {
+        let mut ed = editor(&["foo", "foo"]);
+
+        ed.interrupted.store(true, Ordering::SeqCst);
+        let cmd = Command::Substitute {
+            start: Some(Address::Numbered(1)),
+            end: Some(Address::LastLine),
+            arg: Some("/foo/bar/".into()),
+        };
+        assert!(cmd.execute(&mut ed).is_err());
+        assert_eq!(vec!["foo", "foo"], &ed.data[..]);
+
+        // The next command starts afresh
+        ed.dispatch("1,2s/foo/bar/").unwrap();
+        assert_eq!(vec!["bar", "bar"], &ed.data[..]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn interrupted_global_keeps_buffer() {
+        let mut ed = editor(&["a", "a", "a"]);
+        ed.dirty = false;
+
+        // Interrupt while the command list runs on the first line
+        let interrupted = ed.interrupted.clone();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            interrupted.store(true, Ordering::SeqCst);
+        });
+        ed.dispatch("g/a/s/a/b/\\").unwrap();
+        let err = ed.dispatch("!sleep 1").unwrap_err();
+        interrupter.join().unwrap();
+
+        assert_eq!("Interrupted", err.to_string());
+        assert_eq!(vec!["a", "a", "a"], ed.data);
+        assert_eq!(3, ed.current_line);
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn substitute_single_address() {
+        let mut ed = editor(&["foo", "foo", "foo"]);
+
+        // Only the addressed line, not up to the current one
+        ed.dispatch("1s/foo/bar/").unwrap();
+        assert_eq!(vec!["bar", "foo", "foo"], &ed.data[..]);
+        assert_eq!(1, ed.current_line);
+
+        ed.dispatch("3s/foo/bar/").unwrap();
+        assert_eq!(vec!["bar", "foo", "bar"], &ed.data[..]);
+        assert_eq!(3, ed.current_line);
+    }
+
+    #[test]
+    fn yank_and_put_filtered() {
+        let mut ed = editor(&["apple", "banana", "avocado", "cherry"]);
+
+        ed.dispatch("1,4ya").unwrap();
+        assert_eq!(4, ed.current_line);
+        ed.dispatch("1xa/^a/").unwrap();
+        assert_eq!(
+            vec!["apple", "apple", "avocado", "banana", "avocado", "cherry"],
+            &ed.data[..]
+        );
+        assert_eq!(3, ed.current_line);
+
+        assert!(ed.dispatch("xa/^z/").is_err());
+        assert!(ed.dispatch("xb").is_err());
+
+        ed.dispatch("2,3y").unwrap();
+        ed.dispatch("0x").unwrap();
+        assert_eq!(vec!["apple", "avocado", "apple"], &ed.data[..3]);
+    }
+
+    #[test]
+    fn search_addresses() {
+        let mut ed = Red::new("".into(), None);
+        ed.data = lines(&["foo", "main() {", "bar", "} // end of main"]);
+        ed.current_line = 1;
+
+        ed.dispatch("/main/").unwrap();
+        assert_eq!(2, ed.current_line);
+        // Command letters in the pattern are not commands
+        ed.dispatch("/end of/").unwrap();
+        assert_eq!(4, ed.current_line);
+        // The search wraps around
+        ed.dispatch("/o/").unwrap();
+        assert_eq!(1, ed.current_line);
+
+        ed.dispatch("1m/end of/").unwrap();
+        assert_eq!(vec!["main() {", "bar", "} // end of main", "foo"], ed.data);
+        ed.dispatch("1t?bar?").unwrap();
+        assert_eq!(
+            vec!["main() {", "bar", "main() {", "} // end of main", "foo"],
+            ed.data
+        );
+        ed.dispatch("/end of/-1").unwrap();
+        assert_eq!(3, ed.current_line);
+        ed.dispatch("?main?+1;+1d").unwrap();
+        assert_eq!(vec!["main() {", "} // end of main", "foo"], ed.data);
+        ed.dispatch("1;/}/d").unwrap();
+        assert_eq!(vec!["foo"], ed.data);
+
+        assert!(ed.dispatch("/nowhere/").is_err());
+    }
+
+    #[test]
+    fn repeat_last_pattern() {
+        let mut ed = Red::new("".into(), None);
+        assert!(ed.dispatch("//").is_err());
+        ed.data = lines(&["a1", "b", "a2", "a3"]);
+        ed.current_line = 1;
+
+        ed.dispatch("/a/").unwrap();
+        assert_eq!(3, ed.current_line);
+        ed.dispatch("//").unwrap();
+        assert_eq!(4, ed.current_line);
+        ed.dispatch("??").unwrap();
+        assert_eq!(3, ed.current_line);
+
+        // Substitutions share the pattern with searches
+        ed.dispatch("s//A/").unwrap();
+        assert_eq!("A2", ed.data[2]);
+        ed.dispatch("s/b/B/").unwrap_err();
+        ed.dispatch("1s//x/").unwrap_err();
+        ed.dispatch("??").unwrap();
+        assert_eq!(2, ed.current_line);
+        // The pattern used last by `g` is the one of the command it ran
+        ed.dispatch("g/[0-9]/s/$/!/").unwrap();
+        ed.dispatch(",s//#/").unwrap();
+        assert_eq!(vec!["a1!#", "b#", "A2!#", "a3!#"], ed.data);
+    }
+
+    #[test]
+    fn put_deleted_lines() {
+        let mut ed = Red::new("".into(), None);
+        ed.data = lines(&["one", "two", "three", "four"]);
+
+        ed.dispatch("1,2d").unwrap();
+        ed.dispatch("$x").unwrap();
+        assert_eq!(vec!["three", "four", "one", "two"], ed.data);
+        assert_eq!(4, ed.current_line);
+
+        // Changed lines are kept as well, named registers are left alone
+        ed.dispatch("1ya").unwrap();
+        ed.dispatch("2c").unwrap();
+        ed.dispatch("FOUR").unwrap();
+        ed.dispatch(".").unwrap();
+        ed.dispatch("0x").unwrap();
+        ed.dispatch("0xa").unwrap();
+        assert_eq!(
+            vec!["three", "four", "three", "FOUR", "one", "two"],
+            ed.data
+        );
+    }
+
+    #[test]
+    fn split_and_insert_at_column() {
+        let mut ed = editor(&["\tfoo bar"]);
+        ed.tab_width = 4;
+
+        ed.dispatch("1:9S").unwrap();
+        assert_eq!(vec!["\tfoo ", "bar"], &ed.data[..]);
+        assert_eq!(2, ed.current_line);
+
+        ed.dispatch("1:5I new").unwrap();
+        assert_eq!(vec!["\tnewfoo ", "bar"], &ed.data[..]);
+        assert_eq!(1, ed.current_line);
+
+        ed.dispatch("2:b1I >").unwrap();
+        assert_eq!(vec!["\tnewfoo ", ">bar"], &ed.data[..]);
+    }
+
+    #[test]
+    fn compress_blank_lines() {
+        let mut ed = editor(&["a", "", "  ", "b", "", "c", "", ""]);
+
+        ed.dispatch("Bs").unwrap();
+        assert_eq!(vec!["a", "", "b", "", "c", ""], &ed.data[..]);
+        assert_eq!(6, ed.current_line);
+
+        ed.dispatch("1,4B").unwrap();
+        assert_eq!(vec!["a", "b", "c", ""], &ed.data[..]);
+        assert_eq!(2, ed.current_line);
+
+        ed.dispatch("B").unwrap();
+        assert_eq!(vec!["a", "b", "c"], &ed.data[..]);
+        assert_eq!(3, ed.current_line);
+    }
+
+    #[test]
+    fn record_session() {
+        let file = TempFile::new("record-test.ed");
+        let path = file.path.clone();
+
+        let mut ed = editor(&["Line 1", "Line 2", "Line 3"]);
+        assert!(ed.dispatch("7d").is_err());
+        ed.dispatch("2d").unwrap();
+        ed.dispatch(&format!("R {}", path)).unwrap();
+
+        let script = fs::read_to_string(&path).unwrap();
+        assert_eq!("a\nLine 1\nLine 2\nLine 3\n.\n2d\n", script);
+
+        // Replaying the script reproduces the edits
+        let mut replay = Red::new("".into(), None);
+        for line in script.lines() {
+            replay.dispatch(line).unwrap();
+        }
+        assert_eq!(ed.data, replay.data);
+    }
+
+    #[test]
+    fn write_patch() {
+        let file = TempFile::new("patch-test.txt");
+        let path = file.path.clone();
+        let patch = TempFile {
+            path: format!("{}.patch", path),
+        };
+        fs::write(&path, "one\ntwo\nthree\n").unwrap();
+
+        let mut ed = Red::new("".into(), Some(path.clone()));
+        assert!(ed.dispatch("U").is_err());
+
+        ed.dispatch("2d").unwrap();
+        ed.dispatch("U").unwrap();
+        let expected = format!(
+            "--- a/{0}\n+++ b/{0}\n@@ -1,3 +1,2 @@\n one\n-two\n three\n",
+            path
+        );
+        assert_eq!(expected, fs::read_to_string(&patch.path).unwrap());
+
+        // After writing, the changes are part of the file
+        ed.dispatch("w").unwrap();
+        assert!(ed.dispatch("U").is_err());
+    }
+
+    #[test]
+    fn apply_sed_file() {
+        let file = TempFile::new("sed-test.sed");
+        let path = file.path.clone();
+        fs::write(&path, "# fix typos\ns/teh/the/g\n\ns/nothing//\ns/^/> /\n").unwrap();
+
+        let mut ed = Red::new("".into(), None);
+        ed.data = vec!["teh cat and teh dog".into(), "fine".into()];
+        ed.apply_sed_file(&path).unwrap();
+        assert_eq!(vec!["> the cat and the dog", "> fine"], ed.data);
+        assert!(ed.dirty);
+
+        fs::write(&path, "s/a/b/\n2d\n").unwrap();
+        let err = ed.apply_sed_file(&path).unwrap_err();
+        assert_eq!(format!("{}:2: Invalid substitution", path), err.to_string());
+
+        fs::write(&path, "s/(/x/\n").unwrap();
+        let err = ed.apply_sed_file(&path).unwrap_err();
+        assert_eq!(format!("{}:1: Invalid pattern", path), err.to_string());
+    }
+
+    #[test]
+    fn undo_last_change() {
+        let mut ed = Red::new("".into(), None);
+        assert!(ed.dispatch("u").is_err());
+
+        ed.dispatch("a").unwrap();
+        ed.dispatch("Line 1").unwrap();
+        ed.dispatch("Line 2").unwrap();
+        ed.dispatch("Line 3").unwrap();
+        ed.dispatch("Line 4").unwrap();
+        ed.dispatch(".").unwrap();
+        ed.dispatch("2").unwrap();
+        ed.dispatch("2,3d").unwrap();
+        assert_eq!(vec!["Line 1", "Line 4"], ed.data);
+
+        ed.dispatch("u").unwrap();
+        assert_eq!(vec!["Line 1", "Line 2", "Line 3", "Line 4"], ed.data);
+        assert_eq!(2, ed.current_line);
+
+        // Undoing again redoes the change
+        ed.dispatch("u").unwrap();
+        assert_eq!(vec!["Line 1", "Line 4"], ed.data);
+        assert_eq!(2, ed.current_line);
+
+        // A failed command leaves the last change to undo
+        assert!(ed.dispatch("s/nothing/else/").is_err());
+        ed.dispatch("u").unwrap();
+        assert_eq!(4, ed.lines());
+    }
+
+    #[test]
+    fn undo_input_and_dirty() {
+        let file = TempFile::new("undo-test.txt");
+        let path = file.path.clone();
+        fs::write(&path, "one\n").unwrap();
+
+        let mut ed = Red::new("".into(), Some(path.clone()));
+        ed.dispatch("a").unwrap();
+        ed.dispatch("two").unwrap();
+        ed.dispatch("three").unwrap();
+        ed.dispatch(".").unwrap();
+        assert!(ed.dirty);
+
+        ed.dispatch("u").unwrap();
+        assert_eq!(vec!["one"], ed.data);
+        assert_eq!(1, ed.current_line);
+        assert!(!ed.dirty);
+
+        ed.dispatch("u").unwrap();
+        assert_eq!(vec!["one", "two", "three"], ed.data);
+        assert!(ed.dirty);
+
+        // Loading a file forgets what there was to undo
+        ed.dispatch("w").unwrap();
+        ed.dispatch(&format!("e {}", path)).unwrap();
+        assert!(ed.dispatch("u").is_err());
+    }
+
+    #[test]
+    fn repeat_input() {
+        let mut ed = Red::new("".into(), None);
+        assert!(ed.dispatch("M").is_err());
+
+        ed.dispatch("a").unwrap();
+        ed.dispatch("one").unwrap();
+        ed.dispatch("two").unwrap();
+        ed.dispatch(".").unwrap();
+        ed.dispatch("0M").unwrap();
+        assert_eq!(vec!["one", "two", "one", "two"], ed.data);
+        assert_eq!(2, ed.current_line);
+
+        // Empty input keeps the previous lines
+        ed.dispatch("i").unwrap();
+        ed.dispatch(".").unwrap();
+        ed.dispatch("$M").unwrap();
+        assert_eq!(6, ed.lines());
+        assert_eq!(6, ed.current_line);
+    }
+
+    #[test]
+    fn interactive_global_commands() {
+        let mut ed = Red::new("".into(), None);
+        ed.data = lines(&["foo 1", "bar", "foo 2", "foo 3", "foo 4"]);
+
+        ed.dispatch("G/foo/").unwrap();
+        assert_eq!(Mode::Global, ed.mode);
+        assert_eq!(1, ed.current_line);
+        ed.dispatch("s/foo/baz/").unwrap();
+        assert_eq!(3, ed.current_line);
+        // An empty line skips a line
+        ed.dispatch("").unwrap();
+        assert_eq!(4, ed.current_line);
+        ed.dispatch("d").unwrap();
+        assert_eq!(4, ed.current_line);
+        ed.dispatch("&").unwrap();
+        assert_eq!(Mode::Command, ed.mode);
+        assert_eq!(vec!["baz 1", "bar", "foo 2"], ed.data);
+
+        // All changes are undone at once
+        ed.dispatch("u").unwrap();
+        assert_eq!(vec!["foo 1", "bar", "foo 2", "foo 3", "foo 4"], ed.data);
+        ed.dispatch("u").unwrap();
+        assert_eq!(vec!["baz 1", "bar", "foo 2"], ed.data);
+
+        // Matched lines are followed when other lines are deleted or moved
+        ed.data = lines(&["x", "x", "y"]);
+        ed.dispatch("G/x/").unwrap();
+        ed.dispatch("+1d").unwrap();
+        assert_eq!(Mode::Command, ed.mode);
+        assert_eq!(vec!["x", "y"], ed.data);
+        ed.data = lines(&["a1", "b", "a2", "c"]);
+        ed.dispatch("G/a/").unwrap();
+        ed.dispatch("m$").unwrap();
+        assert_eq!(2, ed.current_line);
+        ed.dispatch("&").unwrap();
+        assert_eq!(vec!["b", "c", "a1", "a2"], ed.data);
+        ed.data = lines(&["baz 1", "bar", "foo 2"]);
+
+        // Errors end the global command
+        ed.dispatch("V/foo/").unwrap();
+        assert_eq!(1, ed.current_line);
+        assert!(ed.dispatch("a").is_err());
+        assert_eq!(Mode::Command, ed.mode);
+        assert!(ed.global_lines.is_empty());
+    }
+
+    #[test]
+    fn global_commands() {
+        let mut ed = Red::new("".into(), None);
+        ed.data = lines(&["foo 1", "bar", "foo 2", "foo 3", "baz"]);
+
+        ed.dispatch("g/foo/d").unwrap();
+        assert_eq!(vec!["bar", "baz"], ed.data);
+
+        ed.dispatch("v/z/s/$/!/").unwrap();
+        assert_eq!(vec!["bar!", "baz"], ed.data);
+        assert_eq!(1, ed.current_line);
+
+        // Lines deleted by an earlier match are skipped
+        ed.data = lines(&["x", "x", "y", "x", "y"]);
+        ed.dispatch("g/x/.,+1d").unwrap();
+        assert_eq!(vec!["y"], ed.data);
+
+        // A matched line deleted while visiting another one is not visited
+        ed.data = lines(&["x", "x", "y"]);
+        ed.dispatch("g/x/+1d").unwrap();
+        assert_eq!(vec!["x", "y"], ed.data);
+
+        // Matched lines are followed when they move
+        ed.data = lines(&["a1", "b", "a2", "c"]);
+        ed.dispatch("g/a/m$").unwrap();
+        assert_eq!(vec!["b", "c", "a1", "a2"], ed.data);
+        assert!(ed.global_lines.is_empty());
+
+        // Lines added by the command are not visited
+        ed.data = lines(&["a", "b", "a"]);
+        ed.registers.clear();
+        ed.dispatch("g/a/x").unwrap_err();
+        ed.dispatch("1y").unwrap();
+        ed.dispatch("g/a/x").unwrap();
+        assert_eq!(vec!["a", "a", "b", "a", "a"], ed.data);
+
+        // The whole global command is undone at once
+        ed.dispatch("u").unwrap();
+        assert_eq!(vec!["a", "b", "a"], ed.data);
+
+        // Without a command list, `a` has no text to add
+        ed.dispatch("g/a/a").unwrap();
+        assert_eq!(Mode::Command, ed.mode);
+        assert_eq!(vec!["a", "b", "a"], ed.data);
+
+        // A substitution goes on with the next line if it doesn't match one
+        ed.data = lines(&["a b", "a", "a b"]);
+        ed.dispatch("g/a/s/b/c/").unwrap();
+        assert_eq!(vec!["a c", "a", "a c"], ed.data);
+        assert_eq!(3, ed.current_line);
+        let err = ed.dispatch("g/a/s/x/y/").unwrap_err();
+        assert_eq!("No match", err.to_string());
+        assert_eq!(vec!["a c", "a", "a c"], ed.data);
+
+        // Any other error leaves the buffer as it was
+        ed.data = lines(&["a", "b", "a"]);
+        ed.current_line = 2;
+        ed.dirty = false;
+        ed.dispatch("g/a/s/a/x/\\").unwrap();
+        let err = ed.dispatch("+3d").unwrap_err();
+        assert_eq!("Invalid address", err.to_string());
+        assert_eq!(vec!["a", "b", "a"], ed.data);
+        assert_eq!(2, ed.current_line);
+        assert!(!ed.dirty);
+    }
+
+    #[test]
+    fn failed_command_keeps_undo() {
+        let mut ed = editor(&["a", "b"]);
+        ed.dispatch("1d").unwrap();
+
+        // The failing command changed nothing, the last change is still undone
+        ed.dispatch("g/b/s/b/x/\\").unwrap();
+        assert!(ed.dispatch("+3d").is_err());
+        assert_eq!(vec!["b"], ed.data);
+        ed.dispatch("u").unwrap();
+        assert_eq!(vec!["a", "b"], ed.data);
+    }
+
+    #[test]
+    fn global_command_lists() {
+        let mut ed = Red::new("".into(), None);
+        ed.data = lines(&["foo 1", "bar", "foo 2"]);
+
+        ed.dispatch("g/foo/s/foo/baz/\\").unwrap();
+        assert_eq!(vec!["foo 1", "bar", "foo 2"], ed.data);
+        ed.dispatch("s/$/!/").unwrap();
+        assert_eq!(vec!["baz 1!", "bar", "baz 2!"], ed.data);
+
+        // Text for `a` goes on with backslashes, the final `.` can be left out
+        ed.dispatch("g/baz/a\\").unwrap();
+        ed.dispatch("after\\").unwrap();
+        ed.dispatch("more").unwrap();
+        assert_eq!(
+            vec!["baz 1!", "after", "more", "bar", "baz 2!", "after", "more"],
+            ed.data
+        );
+        assert_eq!(Mode::Command, ed.mode);
+        assert_eq!(7, ed.current_line);
+
+        ed.dispatch("g/after/i\\").unwrap();
+        ed.dispatch("before\\").unwrap();
+        ed.dispatch(".\\").unwrap();
+        ed.dispatch("++d").unwrap();
+        assert_eq!(
+            vec!["baz 1!", "before", "after", "bar", "baz 2!", "before", "after"],
+            ed.data
+        );
+
+        // The whole list is undone at once
+        ed.dispatch("u").unwrap();
+        assert_eq!(
+            vec!["baz 1!", "after", "more", "bar", "baz 2!", "after", "more"],
+            ed.data
+        );
+
+        // Global commands can't be nested
+        ed.dispatch("g/bar/\\").unwrap();
+        assert!(ed.dispatch("g/x/p").is_err());
+        assert_eq!(Mode::Command, ed.mode);
+    }
+
+    #[test]
+    fn follow_appended_lines() {
+        let file = TempFile::new("follow-test.log");
+        let path = file.path.clone();
+        fs::write(&path, "first\n").unwrap();
+
+        let mut ed = Red::new("".into(), Some(path.clone()));
+        ed.read_only = true;
+        ed.follow_offset = fs::metadata(&path).unwrap().len();
+        assert!(ed.read_appended().unwrap().is_empty());
+
+        fs::write(&path, "first\nsecond\nthird\npartial").unwrap();
+        assert_eq!(vec!["second", "third"], ed.read_appended().unwrap());
+        assert_eq!(vec!["first", "second", "third"], &ed.data[..]);
+        assert_eq!(3, ed.current_line);
+
+        fs::write(&path, "new\n").unwrap();
+        assert_eq!(vec!["new"], ed.read_appended().unwrap());
+        assert_eq!(vec!["new"], &ed.data[..]);
+
+        assert!(ed.dispatch("1d").is_err());
+        assert!(ed.dispatch("a").is_err());
+        ed.dispatch("1;").unwrap();
+    }
+
+    #[test]
+    fn wrap_in_block() {
+        let mut ed = editor(&["fn main() {", "    let x = 1;", "", "    println!(x);", "}"]);
+
+        ed.dispatch("2,4{").unwrap();
+        assert_eq!(
+            vec![
+                "fn main() {",
+                "    {",
+                "        let x = 1;",
+                "",
+                "        println!(x);",
+                "    }",
+                "}",
+            ],
+            &ed.data[..]
+        );
+        assert_eq!(6, ed.current_line);
+
+        ed.block_style = BlockStyle::DoEnd;
+        ed.dispatch("1{").unwrap();
+        assert_eq!(vec!["do", "    fn main() {", "end"], &ed.data[..3]);
+    }
+
+    #[test]
+    fn print_suffixes() {
+        let mut ed = Red::new("".into(), None);
+        ed.data = lines(&["a", "b", "c", "d"]);
+        ed.output = Some(vec![]);
+
+        ed.dispatch("1,2jp").unwrap();
+        assert_eq!(vec!["ab", "c", "d"], ed.data);
+        assert_eq!("ab\n", printed(&mut ed));
+        ed.dispatch("1t$l").unwrap();
+        assert_eq!(4, ed.current_line);
+        assert_eq!("ab$\n", printed(&mut ed));
+        ed.dispatch("$m0n").unwrap();
+        assert_eq!(vec!["ab", "ab", "c", "d"], ed.data);
+        assert_eq!("1\tab\n", printed(&mut ed));
+        ed.dispatch("1,$s/b/x/gn").unwrap();
+        assert_eq!(vec!["ax", "ax", "c", "d"], ed.data);
+        assert_eq!("2\tax\n", printed(&mut ed));
+        ed.dispatch("3dp").unwrap();
+        assert_eq!(vec!["ax", "ax", "d"], ed.data);
+        assert_eq!("d\n", printed(&mut ed));
+        assert!(ed.dispatch("1jx").is_err());
+        assert_eq!("", printed(&mut ed));
+    }
+
+    #[test]
+    fn print_suffix_after_input() {
+        let mut ed = Red::new("".into(), None);
+        ed.dispatch("an").unwrap();
+        assert_eq!(Some(LineFormat::Numbered), ed.input_print);
+        ed.dispatch("first").unwrap();
+        ed.dispatch(".").unwrap();
+        assert_eq!(None, ed.input_print);
+        assert_eq!(vec!["first"], ed.data);
+    }
+
+    #[test]
+    fn auto_print_after_changes() {
+        let mut ed = editor(&["Line 1", "Line 2"]);
+        ed.auto_print = true;
+        ed.output = Some(vec![]);
+
+        // The `p` flag doesn't print the line a second time
+        ed.dispatch("s/Line/line/p").unwrap();
+        assert_eq!("line 2\n", printed(&mut ed));
+        ed.dispatch("1d").unwrap();
+        assert_eq!(vec!["line 2"], &ed.data[..]);
+        assert_eq!("line 2\n", printed(&mut ed));
+        ed.dispatch("1p").unwrap();
+        assert_eq!("line 2\n", printed(&mut ed));
+        // Nothing left to print
+        ed.dispatch("1d").unwrap();
+        assert!(ed.data.is_empty());
+        assert_eq!("", printed(&mut ed));
+    }
+
+    #[test]
+    fn interleave_ranges() {
+        let mut ed = editor(&["a1", "a2", "a3", "x", "b1", "b2"]);
+
+        ed.dispatch("1,3Z5,6").unwrap();
+        assert_eq!(vec!["a1", "b1", "a2", "b2", "a3", "x"], &ed.data[..]);
+        assert_eq!(5, ed.current_line);
+
+        ed.dispatch("6Z1,2").unwrap();
+        assert_eq!(vec!["a2", "b2", "a3", "x", "a1", "b1"], &ed.data[..]);
+        assert_eq!(6, ed.current_line);
+
+        assert!(ed.dispatch("1,3Z2,4").is_err());
+    }
 }
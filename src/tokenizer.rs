@@ -3,19 +3,65 @@ use failure;
 static COMMANDS: &'static [char] = &[
     'p', // print
     'n', // numbered print
+    'N', // snippet print
+    'l', // list
+    'z', // scroll
     'w', // write [arg]
+    'W', // append to file [arg]
     'd', // delete
     'a', // append
     'i', // insert
     'c', // replace line
     'h', // show last error
+    'H', // toggle error explanations
     'q', // quit
     'Q', // Force-quit
+    'P', // toggle prompt
     'e', // edit file
+    'f', // filename
     'c', // change
     'r', // read
     'm', // move
     's', // substitute
+    '#', // comment
+    'o', // print only the matched group
+    '@', // rotate lines
+    'L', // long lines
+    'D', // diff against a file
+    'O', // outline
+    'T', // translate characters
+    'Y', // copy to clipboard
+    'C', // check format
+    'y', // yank
+    'x', // put
+    'S', // split line at column
+    'I', // insert text at column
+    'B', // remove blank lines
+    '=', // line number
+    'R', // record session
+    '{', // wrap in block
+    'Z', // interleave
+    'A', // print with change markers
+    'G', // interactive global
+    'V', // inverted interactive global
+    'k', // mark
+    'X', // transpose
+    'J', // pretty-print JSON
+    'U', // write a patch
+    '~', // change case
+    '_', // insert a ruler
+    'K', // cut columns
+    'u', // undo
+    'M', // repeat last input
+    'g', // global
+    'v', // inverted global
+    'E', // escape
+    'F', // align columns
+    'j', // join
+    't', // transfer
+    '*', // insert every nth line
+    '!', // shell escape
+    '|', // toggle soft wrap
 ];
 
 #[derive(Debug, PartialEq, Eq)]
@@ -30,7 +76,34 @@ pub enum Token<'a> {
 pub fn tokenize(line: &str) -> Result<Vec<Token>, failure::Error> {
     let mut res = vec![];
 
-    let command_idx = line.find(|c: char| COMMANDS.contains(&c));
+    // Find the command and the separator between two addresses.
+    // The character after a `'` names a mark and search patterns are skipped,
+    // so neither is mistaken for a command.
+    let mut chars = line.char_indices();
+    let mut command_idx = None;
+    let mut addr_separator_idx = None;
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\'' => {
+                chars.next();
+            }
+            '/' | '?' => {
+                while let Some((_, p)) = chars.next() {
+                    if p == '\\' {
+                        chars.next();
+                    } else if p == c {
+                        break;
+                    }
+                }
+            }
+            ',' | ';' if addr_separator_idx.is_none() => addr_separator_idx = Some(idx),
+            c if COMMANDS.contains(&c) => {
+                command_idx = Some(idx);
+                break;
+            }
+            _ => {}
+        }
+    }
     debug!("command idx: {:?}", command_idx);
 
     let addr_part = match command_idx {
@@ -38,8 +111,6 @@ pub fn tokenize(line: &str) -> Result<Vec<Token>, failure::Error> {
         Some(idx) => &line[0..idx],
     };
     debug!("addr part: {:?}", addr_part);
-
-    let addr_separator_idx = addr_part.find(|c| [',', ';'].contains(&c));
     debug!("addr sep idx: {:?}", addr_separator_idx);
 
     let rest_addr = match addr_separator_idx {
@@ -69,6 +140,20 @@ pub fn tokenize(line: &str) -> Result<Vec<Token>, failure::Error> {
         }
     };
 
+    // Everything after a comment is ignored
+    if res.last() == Some(&Token::Command('#')) {
+        return Ok(res);
+    }
+
+    // A shell command is taken as it is
+    if res.last() == Some(&Token::Command('!')) {
+        let arg = line[after_cmd_idx..].trim();
+        if !arg.is_empty() {
+            res.push(Token::Argument(arg));
+        }
+        return Ok(res);
+    }
+
     if after_cmd_idx < line.len() {
         let suffix_char = line[after_cmd_idx..=after_cmd_idx].chars().next().unwrap();
         if suffix_char == ' ' {
@@ -186,4 +271,47 @@ mod test {
         ];
         assert_eq!(expected, tokenize("1,2m3 param").unwrap());
     }
+
+    #[test]
+    fn comment_ends_tokenizing() {
+        let expected = vec![Token::Address("12"), Token::Command('#')];
+        assert_eq!(expected, tokenize("12#this line is interesting").unwrap());
+    }
+
+    #[test]
+    fn shell_command_is_one_argument() {
+        let expected = vec![Token::Command('!'), Token::Argument("ls -l  src/")];
+        assert_eq!(expected, tokenize("!ls -l  src/").unwrap());
+
+        let expected = vec![Token::Command('!')];
+        assert_eq!(expected, tokenize("!").unwrap());
+    }
+
+    #[test]
+    fn mark_is_not_a_command() {
+        let expected = vec![
+            Token::Address("'a+3"),
+            Token::Separator(','),
+            Token::Address("'p"),
+            Token::Command('p'),
+        ];
+        assert_eq!(expected, tokenize("'a+3,'pp").unwrap());
+    }
+
+    #[test]
+    fn search_addresses() {
+        let expected = vec![Token::Address("/a,d/"), Token::Command('d')];
+        assert_eq!(expected, tokenize("/a,d/d").unwrap());
+
+        let expected = vec![
+            Token::Address("/start/"),
+            Token::Separator(','),
+            Token::Address("?e\\?nd?"),
+            Token::Command('p'),
+        ];
+        assert_eq!(expected, tokenize("/start/,?e\\?nd?p").unwrap());
+
+        let expected = vec![Token::Address("/main")];
+        assert_eq!(expected, tokenize("/main").unwrap());
+    }
 }